@@ -0,0 +1,129 @@
+/// Hamming distance between two bit strings, bits beyond the shorter one all
+/// count as different.
+pub fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    let common: u32 = a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum();
+    let extra = a.len().max(b.len()) - a.len().min(b.len());
+    common + 8 * extra as u32
+}
+
+struct Node<T> {
+    key: Vec<u8>,
+    value: T,
+    // keyed by the distance to this node
+    children: Vec<(u32, Node<T>)>,
+}
+
+/// BK-tree over Hamming distance, finds every key within a distance of a
+/// query without comparing against all of them.
+pub struct BkTree<T> {
+    root: Option<Node<T>>,
+    len: usize,
+}
+
+impl<T> Default for BkTree<T> {
+    fn default() -> Self {
+        BkTree { root: None, len: 0 }
+    }
+}
+
+impl<T> BkTree<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts `key`, replacing the value if the key is already there.
+    pub fn insert(&mut self, key: Vec<u8>, value: T) {
+        let mut node = match &mut self.root {
+            Some(node) => node,
+            None => {
+                self.root = Some(Node { key, value, children: vec![] });
+                self.len = 1;
+                return;
+            }
+        };
+        loop {
+            let dist = hamming(&node.key, &key);
+            if dist == 0 {
+                node.value = value;
+                return;
+            }
+            match node.children.iter().position(|(d, _)| *d == dist) {
+                Some(i) => node = &mut node.children[i].1,
+                None => {
+                    node.children.push((dist, Node { key, value, children: vec![] }));
+                    self.len += 1;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Every value whose key is at most `max_dist` away from `key`, with its
+    /// distance, closest first.
+    pub fn find(&self, key: &[u8], max_dist: u32) -> Vec<(u32, &T)> {
+        let mut found = vec![];
+        let mut stack: Vec<&Node<T>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            let dist = hamming(&node.key, key);
+            if dist <= max_dist {
+                found.push((dist, &node.value));
+            }
+            // by the triangle inequality, nothing else can be close enough
+            let range = dist.saturating_sub(max_dist)..=dist.saturating_add(max_dist);
+            stack.extend(node.children.iter()
+                             .filter(|(d, _)| range.contains(d))
+                             .map(|(_, child)| child));
+        }
+        found.sort_by_key(|(dist, _)| *dist);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> BkTree<&'static str> {
+        let mut tree = BkTree::default();
+        for (key, value) in [(0b0000_0000, "a"), (0b0000_0001, "b"), (0b0000_0011, "c"),
+                             (0b0000_0111, "d"), (0b1111_1111, "e")] {
+            tree.insert(vec![key], value);
+        }
+        tree
+    }
+
+    #[test]
+    fn finds_every_key_within_the_distance() {
+        let tree = tree();
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.find(&[0b0000_0000], 0), vec![(0, &"a")]);
+        assert_eq!(tree.find(&[0b0000_0001], 1), vec![(0, &"b"), (1, &"a"), (1, &"c")]);
+        assert_eq!(tree.find(&[0b1111_0000], 3).len(), 0);
+        assert_eq!(tree.find(&[0b0000_0000], 8).len(), 5);
+    }
+
+    #[test]
+    fn the_boundary_distance_is_included() {
+        let tree = tree();
+        // "d" is exactly 3 away, "e" 8
+        assert_eq!(tree.find(&[0b0000_0000], 3).last(), Some(&(3, &"d")));
+        assert_eq!(tree.find(&[0b0000_0000], 2).last(), Some(&(2, &"c")));
+        assert_eq!(tree.find(&[0b0000_0000], 7).len(), 4);
+        // bits beyond the shorter key all count
+        assert_eq!(tree.find(&[0b0000_0000, 0], 8).last(), Some(&(8, &"a")));
+    }
+
+    #[test]
+    fn an_empty_tree_finds_nothing() {
+        let mut tree = BkTree::<()>::default();
+        assert!(tree.is_empty());
+        assert!(tree.find(&[0], u32::MAX).is_empty());
+        tree.insert(vec![1], ());
+        tree.insert(vec![1], ());
+        assert_eq!(tree.len(), 1);
+    }
+}
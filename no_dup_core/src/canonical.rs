@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use url::Url;
+
+/// Rewrites a URL of one site into the form it is stored under.
+pub type SiteRule = fn(&mut Url);
+
+// query parameters that only tell where a link was shared from
+static TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid",
+    "ref_src", "ref_url", "spm", "share_source", "share_medium", "_ga",
+];
+
+// host prefixes that serve the same pages as the bare domain
+static MIRROR_PREFIXES: &[&str] = &["www.", "m.", "mobile."];
+
+/// Turns the different spellings of a URL into a single one, so that they map
+/// to the same `MessageKey`. Every URL gets the generic steps: https scheme,
+/// lowercase host without `www.`/`m.`, no fragment, no tracking parameters and
+/// no trailing slash. Sites with their own quirks get a [`SiteRule`] on top,
+/// chosen by the host after the generic steps.
+#[derive(Clone)]
+pub struct Canonicalizer {
+    sites: HashMap<String, SiteRule>,
+}
+
+impl Default for Canonicalizer {
+    fn default() -> Self {
+        Canonicalizer::empty()
+            .with_site("youtube.com", youtube)
+            .with_site("youtu.be", youtu_be)
+            .with_site("twitter.com", twitter)
+            .with_site("x.com", twitter)
+            .with_site("fxtwitter.com", twitter)
+            .with_site("vxtwitter.com", twitter)
+            .with_site("open.spotify.com", drop_query)
+    }
+}
+
+impl Canonicalizer {
+    /// A canonicalizer with the generic steps only.
+    pub fn empty() -> Self {
+        Canonicalizer { sites: HashMap::new() }
+    }
+
+    /// Applies `rule` to URLs on `domain`, replacing any rule it had before.
+    pub fn with_site(mut self, domain: &str, rule: SiteRule) -> Self {
+        self.sites.insert(domain.to_lowercase(), rule);
+        self
+    }
+
+    pub fn canonicalize(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+        if url.cannot_be_a_base() || url.host_str().is_none() {
+            return url;
+        }
+        if url.scheme() == "http" {
+            let _ = url.set_scheme("https");
+        }
+        url.set_fragment(None);
+
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        let host = String::from(strip_mirror_prefix(&host));
+        let _ = url.set_host(Some(&host));
+
+        retain_query(&mut url, |name| !is_tracking_param(name));
+        if let Some(rule) = self.sites.get(&host) {
+            rule(&mut url);
+        }
+
+        let path = url.path().to_string();
+        if path.len() > 1 && path.ends_with('/') {
+            url.set_path(path.trim_end_matches('/'));
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        url
+    }
+}
+
+/// `host` without a `www.`/`m.`/`mobile.` prefix, unless nothing but a top
+/// level domain would be left.
+pub fn strip_mirror_prefix(host: &str) -> &str {
+    MIRROR_PREFIXES.iter()
+                   .find_map(|p| host.strip_prefix(p))
+                   .filter(|h| h.contains('.'))
+                   .unwrap_or(host)
+}
+
+fn is_tracking_param(name: &str) -> bool {
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name)
+}
+
+/// Keeps the query parameters whose name passes `keep`.
+pub fn retain_query<F: Fn(&str) -> bool>(url: &mut Url, keep: F) {
+    if url.query().is_none() {
+        return;
+    }
+    let pairs: Vec<(String, String)> = url.query_pairs()
+                                          .filter(|(name, _)| keep(name))
+                                          .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                                          .collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+}
+
+/// Site rule for sites whose query never tells pages apart.
+pub fn drop_query(url: &mut Url) {
+    url.set_query(None);
+}
+
+// youtube.com/watch?v=<id>, also for shorts
+fn youtube(url: &mut Url) {
+    let short_id = url.path()
+                      .strip_prefix("/shorts/")
+                      .map(|id| id.trim_end_matches('/').to_string());
+    if let Some(id) = short_id {
+        url.set_path("/watch");
+        url.set_query(None);
+        url.query_pairs_mut().append_pair("v", &id);
+    }
+    retain_query(url, |name| name == "v" || name == "list");
+}
+
+// youtu.be/<id> is youtube.com/watch?v=<id>
+fn youtu_be(url: &mut Url) {
+    let id = url.path().trim_matches('/').to_string();
+    if id.is_empty() {
+        return;
+    }
+    let _ = url.set_host(Some("youtube.com"));
+    url.set_path("/watch");
+    let list = url.query_pairs()
+                  .find(|(name, _)| name == "list")
+                  .map(|(_, value)| value.into_owned());
+    url.set_query(None);
+    url.query_pairs_mut().append_pair("v", &id);
+    if let Some(list) = list {
+        url.query_pairs_mut().append_pair("list", &list);
+    }
+}
+
+// twitter.com and its embed mirrors are x.com, whose query is only tracking
+fn twitter(url: &mut Url) {
+    let _ = url.set_host(Some("x.com"));
+    url.set_query(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spellings_map_to_one_url() {
+        let cases = [
+            // youtube keeps only the video and the playlist
+            ("https://www.youtube.com/watch?v=abc&t=42&feature=share", "https://youtube.com/watch?v=abc"),
+            ("https://m.youtube.com/watch?v=abc&list=PL1&index=3", "https://youtube.com/watch?v=abc&list=PL1"),
+            ("https://youtube.com/shorts/abc/?feature=share", "https://youtube.com/watch?v=abc"),
+            ("https://youtu.be/abc?si=xyz", "https://youtube.com/watch?v=abc"),
+            ("https://youtu.be/abc?list=PL1&si=xyz", "https://youtube.com/watch?v=abc&list=PL1"),
+            // twitter and its mirrors
+            ("https://twitter.com/a/status/1?s=20", "https://x.com/a/status/1"),
+            ("https://mobile.twitter.com/a/status/1", "https://x.com/a/status/1"),
+            ("https://x.com/a/status/1?t=abc", "https://x.com/a/status/1"),
+            ("https://fxtwitter.com/a/status/1", "https://x.com/a/status/1"),
+            ("https://vxtwitter.com/a/status/1", "https://x.com/a/status/1"),
+            ("https://open.spotify.com/track/abc?si=xyz&context=foo", "https://open.spotify.com/track/abc"),
+            // tracking parameters, the others are kept
+            ("https://example.com/a?utm_source=x&utm_medium=y&id=1", "https://example.com/a?id=1"),
+            ("https://example.com/a?fbclid=1&gclid=2&spm=3", "https://example.com/a"),
+            // mirrors, scheme, case and fragments
+            ("http://WWW.Example.com/a#top", "https://example.com/a"),
+            ("https://m.example.com/a", "https://example.com/a"),
+            ("https://mobile.example.com/a", "https://example.com/a"),
+            // not a mirror prefix when nothing is left of the domain
+            ("https://www.com/a", "https://www.com/a"),
+            // trailing slashes, but not the root path
+            ("https://example.com/a/b//", "https://example.com/a/b"),
+            ("https://example.com/", "https://example.com/"),
+        ];
+        let canonicalizer = Canonicalizer::default();
+        for (url, expected) in &cases {
+            let canonical = canonicalizer.canonicalize(&Url::parse(url).unwrap());
+            assert_eq!(canonical.as_str(), *expected, "canonical form of {}", url);
+        }
+    }
+
+    #[test]
+    fn site_rules_are_left_out_by_an_empty_canonicalizer() {
+        let url = Url::parse("https://www.youtube.com/watch?v=abc&t=42&utm_source=x").unwrap();
+        assert_eq!(Canonicalizer::empty().canonicalize(&url).as_str(), "https://youtube.com/watch?v=abc&t=42");
+    }
+}
@@ -0,0 +1,821 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{debug, info, trace, warn};
+use url::Url;
+
+use crate::canonical::Canonicalizer;
+use crate::gc::{self, GcConfig, GcReport, Retention};
+use crate::image::{check_img_hashes, contains_img_hash, find_file_id, hash_image_blocking,
+                   insert_file_id, insert_img_hash,
+                   HashConfig, ImageIndex, ImageMetrics, RehashReport, KEYFRAME_PREFIX};
+use crate::image;
+use crate::message::{IncomingMessage, MediaKind};
+use crate::rules::{self, Rule, RuleAction, RuleSet, GLOBAL_RULES};
+use crate::settings::{self, ChatSettings};
+use crate::storage::{Quarantined, Storage, StorageConfig};
+use crate::store::{MessageInfo, MessageKey, Occurrence};
+use crate::top;
+use crate::url_filter::{filter_url, get_url, parse_link};
+
+/// What a duplicate was recognized as, each kind has its own reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKind {
+    // a forwarded channel post
+    Forward,
+    // a link in the text
+    Link,
+    // a photo
+    Image,
+    // any other media, e.g. a video or a sticker
+    Media(MediaKind),
+}
+
+impl DuplicateKind {
+    pub fn of(kind: MediaKind) -> Self {
+        match kind {
+            MediaKind::Photo => DuplicateKind::Image,
+            kind => DuplicateKind::Media(kind),
+        }
+    }
+
+    /// Name used in replies.
+    pub fn name(self) -> &'static str {
+        match self {
+            DuplicateKind::Forward => "消息",
+            DuplicateKind::Link => "链接",
+            DuplicateKind::Image => MediaKind::Photo.name(),
+            DuplicateKind::Media(kind) => kind.name(),
+        }
+    }
+}
+
+/// Something a message carries that was seen before in the chat, within the
+/// window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    // what it was recognized as, e.g. the channel post or the URL
+    pub url: Url,
+    pub kind: DuplicateKind,
+    // how many times it has been seen within the window, including this time
+    pub count: u32,
+    // earlier occurrences in the chat within the window, oldest first
+    pub originals: Vec<Occurrence>,
+}
+
+impl Duplicate {
+    /// Link to the first occurrence in the chat within the window.
+    pub fn first_link(&self) -> Option<Url> {
+        self.originals.first().and_then(|o| o.link.clone())
+    }
+}
+
+/// Outcome of checking a single message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    // the message carries nothing we keep track of
+    Untracked,
+    // nothing in the message was seen in the chat within the window
+    New,
+    // one entry for everything in the message that was seen before
+    Duplicate(Vec<Duplicate>),
+}
+
+/// Only occurrences within this many hours count as duplicates, see 时效性原则
+/// in the PRD.
+pub static DEFAULT_WINDOW_HOURS: i64 = 48;
+
+// One lock per chat, so that a slow chat never holds up the others.
+#[derive(Default)]
+struct ChatLocks {
+    // a few bytes for every chat seen since the last `prune`
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ChatLocks {
+    async fn lock(&self, chat_id: &str) -> OwnedMutexGuard<()> {
+        let lock = self.locks()
+                       .entry(String::from(chat_id))
+                       .or_default()
+                       .clone();
+        lock.lock_owned().await
+    }
+
+    // Drops the locks nobody holds or waits for, they are made again when
+    // needed. Taking the map keeps anyone from picking one up meanwhile.
+    fn prune(&self) {
+        self.locks().retain(|_, lock| Arc::strong_count(lock) > 1);
+    }
+
+    fn locks(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Mutex<()>>>> {
+        self.locks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps track of what has been seen in every chat, and tells whether a new
+/// message is a duplicate.
+///
+/// Messages of a chat are checked one at a time, while chats are checked
+/// independently of each other. Locks are only held within a call, never
+/// across anything the caller awaits, e.g. a reply to Telegram.
+pub struct DuplicateDetector {
+    storage: Arc<dyn Storage>,
+    // held while a message of the chat is checked
+    chat_locks: ChatLocks,
+    // settings of chats that did not configure anything
+    defaults: ChatSettings,
+    canonicalizer: Canonicalizer,
+    image_metrics: ImageMetrics,
+    image_index: ImageIndex,
+    hash_config: HashConfig,
+}
+
+impl DuplicateDetector {
+    /// A detector keeping its records in `storage`.
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        DuplicateDetector {
+            storage,
+            chat_locks: ChatLocks::default(),
+            defaults: ChatSettings::default(),
+            canonicalizer: Canonicalizer::default(),
+            image_metrics: ImageMetrics::default(),
+            image_index: ImageIndex::default(),
+            hash_config: HashConfig::default(),
+        }
+    }
+
+    /// Sets the settings used by chats without settings of their own.
+    pub fn with_defaults(mut self, defaults: ChatSettings) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Sets how URLs in messages are canonicalized before they are compared.
+    pub fn with_canonicalizer(mut self, canonicalizer: Canonicalizer) -> Self {
+        self.canonicalizer = canonicalizer;
+        self
+    }
+
+    /// Sets how images are hashed. Images hashed differently before are no
+    /// longer matched, see [`DuplicateDetector::rehash_images`].
+    pub fn with_hash_config(mut self, hash_config: HashConfig) -> Self {
+        self.hash_config = hash_config;
+        self
+    }
+
+    /// A detector on the storage described by `config`.
+    pub fn open(config: &StorageConfig) -> Result<Self> {
+        Ok(DuplicateDetector::new(config.open()?))
+    }
+
+    /// Records that could not be decoded, set aside so that the others are
+    /// still served.
+    pub fn quarantined(&self) -> Vec<Quarantined> {
+        self.storage.quarantined().unwrap_or_else(|e| {
+            warn!("database error {:?} when listing the quarantine", &e);
+            vec![]
+        })
+    }
+
+    /// Settings in effect for `chat_id`.
+    pub async fn settings(&self, chat_id: &str) -> ChatSettings {
+        settings::find_settings(&*self.storage, chat_id)
+                 .unwrap_or_else(|| self.defaults.clone())
+    }
+
+    pub async fn save_settings(&self, chat_id: &str, settings: &ChatSettings) -> bool {
+        settings::save_settings(&*self.storage, chat_id, settings)
+    }
+
+    /// Makes `chat_id` go back to the default settings.
+    pub async fn reset_settings(&self, chat_id: &str) -> bool {
+        settings::reset_settings(&*self.storage, chat_id)
+    }
+
+    /// Rules stored under `key`, a chat id or [`GLOBAL_RULES`].
+    pub async fn rules_of(&self, key: &str) -> Vec<Rule> {
+        match rules::find_rules(&*self.storage, key) {
+            Some(rules) => rules,
+            None if key == GLOBAL_RULES => rules::default_global_rules(),
+            None => vec![],
+        }
+    }
+
+    /// Rules in effect for `chat_id`.
+    pub async fn rules(&self, chat_id: &str) -> RuleSet {
+        RuleSet {
+            chat: self.rules_of(chat_id).await,
+            global: self.rules_of(GLOBAL_RULES).await,
+        }
+    }
+
+    /// Adds a rule under `key`, replacing any rule with the same pattern.
+    pub async fn add_rule(&self, key: &str, rule: Rule) -> bool {
+        let mut rules = self.rules_of(key).await;
+        rules.retain(|r| r.pattern != rule.pattern);
+        rules.push(rule);
+        rules::save_rules(&*self.storage, key, &rules)
+    }
+
+    /// Removes the rule for `pattern` under `key`, returns whether there was one.
+    pub async fn remove_rule(&self, key: &str, pattern: &str) -> bool {
+        let pattern = Rule::new(pattern, RuleAction::Ignore).pattern;
+        let mut rules = self.rules_of(key).await;
+        let len = rules.len();
+        rules.retain(|r| r.pattern != pattern);
+        len != rules.len() && rules::save_rules(&*self.storage, key, &rules)
+    }
+
+    /// Records the message and reports what it carries that has been seen
+    /// within the window. Whether the earlier occurrences still exist is up to
+    /// the caller to check, see [`DuplicateDetector::forget_occurrence`] and
+    /// [`DuplicateDetector::count_duplicate`].
+    pub async fn check(&self, msg: &IncomingMessage) -> Result<Verdict> {
+        let clean_chat_id = msg.clean_chat_id();
+        let settings = self.settings(&clean_chat_id).await;
+        // hashed before the lock, so that the other messages of the chat only
+        // wait for the database
+        let hashes = match (msg.media_kind(), msg.photo.as_deref()) {
+            (Some(kind), Some(photo)) if !self.is_known_file(msg, &clean_chat_id, &settings) =>
+                self.hash_photo(kind, photo, &settings).await,
+            _ => None,
+        };
+        let _chat = self.chat_locks.lock(&clean_chat_id).await;
+        let rules = self.rules(&clean_chat_id).await;
+
+        let urls = self.urls_of(msg, hashes, &clean_chat_id, &settings, &rules).await;
+        if urls.is_empty() {
+            if let Some(text) = &msg.text {
+                debug!("Msg: {}", text);
+            }
+            return Ok(Verdict::Untracked);
+        }
+
+        let now = Utc::now();
+        let since = settings::hours_before(now, settings.window_hours);
+        let occurrence = Occurrence{seen_at: now, link: msg.link(), user_id: msg.user_id(),
+                                    username: msg.username(), message_id: Some(msg.message_id)};
+        let mut duplicates = vec![];
+        for (url, kind) in urls {
+            if let Some(duplicate) = self.record(&clean_chat_id, url, kind, &occurrence, since).await {
+                duplicates.push(duplicate);
+            }
+        }
+        if duplicates.is_empty() {
+            return Ok(Verdict::New);
+        }
+        Ok(Verdict::Duplicate(duplicates))
+    }
+
+    /// Checks the messages of an album together. They are recorded like single
+    /// messages, but are never duplicates of each other. Returns what each
+    /// message carries that was seen before, in the order given.
+    pub async fn check_album(&self, msgs: &[IncomingMessage]) -> Result<Vec<Vec<Duplicate>>> {
+        let album: HashSet<i32> = msgs.iter().map(|m| m.message_id).collect();
+        let mut checked = vec![];
+        for msg in msgs {
+            let duplicates = match self.check(msg).await? {
+                Verdict::Duplicate(duplicates) => duplicates,
+                _ => vec![],
+            };
+            // e.g. two similar photos in the same album
+            let duplicates = duplicates.into_iter()
+                .filter_map(|mut duplicate| {
+                    let len = duplicate.originals.len();
+                    duplicate.originals.retain(|o| o.message_id.map_or(true, |id| !album.contains(&id)));
+                    duplicate.count -= (len - duplicate.originals.len()) as u32;
+                    Some(duplicate).filter(|d| !d.originals.is_empty())
+                })
+                .collect();
+            checked.push(duplicates);
+        }
+        Ok(checked)
+    }
+
+    /// Counts `duplicates` of `msg`, and the duplicate against its sender on
+    /// the top board, once it is known that an original of each still exists.
+    pub async fn count_duplicate<'a, I>(&self, msg: &IncomingMessage, duplicates: I) -> bool
+    where
+        I: IntoIterator<Item = &'a Duplicate>,
+    {
+        let urls: Vec<Url> = duplicates.into_iter().map(|d| d.url.clone()).collect();
+        top::count_duplicate(&*self.storage, &msg.clean_chat_id(), &urls, &msg.user_id(), &msg.username())
+    }
+
+    /// Drops the occurrence of `url` with `message_id`, e.g. because the
+    /// message was deleted, so that it is no longer offered as an original.
+    pub async fn forget_occurrence(&self, chat_id: &str, url: &Url, message_id: i32) -> bool {
+        let key = MessageKey{chat_id: String::from(chat_id), url: url.clone()};
+        let mut forgot = false;
+        let updated = self.storage.update_message(&key, &mut |info| {
+            forgot = false;
+            info.map(|mut info| {
+                forgot = info.forget(message_id);
+                info
+            })
+        });
+        if let Err(e) = updated {
+            warn!("database error {:?} when forgetting {} in {:?}", &e, message_id, &key);
+            return false;
+        }
+        forgot
+    }
+
+    // Everything the message is recognized by: its media or the channel post
+    // it forwards, and the links in its text.
+    async fn urls_of(&self, msg: &IncomingMessage, hashes: Option<Vec<String>>, clean_chat_id: &str,
+                     settings: &ChatSettings, rules: &RuleSet) -> Vec<(Url, DuplicateKind)> {
+        let mut urls = vec![];
+        let forward_link = msg.forward_link();
+        match (msg.media_kind(), &forward_link) {
+            (Some(kind), _) => {
+                // is an image or other media, possibly also a forward
+                trace!("Found a {} message", kind.as_str());
+                let url = self.media_url(msg, kind, hashes, clean_chat_id, settings).await;
+                urls.extend(url.map(|url| (url, DuplicateKind::of(kind))));
+            },
+            (None, Some(url)) => {
+                trace!("Found a forwarded channel message");
+                urls.push((url.clone(), DuplicateKind::Forward));
+            },
+            (None, None) => {
+                if msg.is_forward() {
+                    trace!("Forwarded message link parse failure.")
+                }
+            }
+        }
+        // a forwarded channel post is recognized as a whole
+        if forward_link.is_none() {
+            let links = self.text_urls(msg, clean_chat_id, settings, rules);
+            urls.extend(links.into_iter().map(|url| (url, DuplicateKind::Link)));
+        }
+        let mut seen = HashSet::new();
+        urls.retain(|(url, _)| seen.insert(url.clone()));
+        urls
+    }
+
+    // Canonical form of the links in the text that pass the rules.
+    fn text_urls(&self, msg: &IncomingMessage, clean_chat_id: &str, settings: &ChatSettings,
+                 rules: &RuleSet) -> Vec<Url> {
+        let urls: Vec<Url> = if msg.links.is_empty() {
+            // without the links spelled out, only a text that is a url as a whole counts
+            msg.text.as_deref().and_then(get_url).into_iter().collect()
+        } else {
+            msg.links.iter().filter_map(|link| parse_link(link)).collect()
+        };
+        if urls.is_empty() {
+            debug!("Non-forwarded message link parse failure.")
+        }
+        urls.iter()
+            .map(|url| self.canonicalizer.canonicalize(url))
+            .filter_map(|url| filter_url(clean_chat_id, Some(url), rules, settings.short_path_len))
+            .collect()
+    }
+
+    // Records an occurrence of `url`, and tells whether it was seen since
+    // `since`.
+    async fn record(&self, clean_chat_id: &str, url: Url, kind: DuplicateKind, occurrence: &Occurrence,
+                    since: DateTime<Utc>) -> Option<Duplicate> {
+        let key = MessageKey{chat_id: String::from(clean_chat_id), url: url.clone()};
+        let mut duplicate = None;
+        let updated = self.storage.update_message(&key, &mut |info| {
+            duplicate = None;
+            match info {
+                Some(mut info) => {
+                    // has seen this message before, but it only matters if it was recent
+                    let recent: Vec<Occurrence> = info.occurrences_since(since).into_iter().cloned().collect();
+                    if recent.is_empty() {
+                        info.record(occurrence.clone());
+                    } else {
+                        // counted along with the top board, see count_duplicate
+                        info.remember(occurrence.clone());
+                        duplicate = Some(Duplicate { url: url.clone(), kind, count: recent.len() as u32 + 1,
+                                                     originals: recent });
+                    }
+                    Some(info)
+                },
+                // has not seen this message before
+                None => Some(MessageInfo::new(url.clone(), occurrence.clone())),
+            }
+        });
+        match (updated, &duplicate) {
+            (Err(e), _) => {
+                warn!("database error {:?} when recording {:?}", &e, &key);
+                return None;
+            },
+            (Ok(_), Some(duplicate)) => info!("See {} {} times within the window", &url, duplicate.count),
+            (Ok(Some(info)), None) if info.count > 1 =>
+                info!("Seen {} times, but none within the window", info.count - 1),
+            _ => (),
+        }
+        duplicate
+    }
+
+    /// Whether media with `file_unique_id` was seen in `chat_id`, in which
+    /// case there is no need to download it.
+    pub async fn knows_file(&self, chat_id: &str, file_unique_id: &str) -> bool {
+        let settings = self.settings(chat_id).await;
+        find_file_id(&*self.storage, file_unique_id, chat_id, settings.timeout_days).is_some()
+    }
+
+    /// How media got recognized since startup.
+    pub fn image_metrics(&self) -> &ImageMetrics {
+        &self.image_metrics
+    }
+
+    // Whether the media of `msg` takes the fast path of `media_url`, so that
+    // there is no need to hash it.
+    fn is_known_file(&self, msg: &IncomingMessage, clean_chat_id: &str, settings: &ChatSettings) -> bool {
+        msg.file_unique_id.as_deref().is_some_and(|file_unique_id| {
+            find_file_id(&*self.storage, file_unique_id, clean_chat_id, settings.timeout_days).is_some()
+        })
+    }
+
+    // Hashes of an image, or of a frame of a video, which are only compared
+    // with frames.
+    async fn hash_photo(&self, kind: MediaKind, photo: &[u8], settings: &ChatSettings) -> Option<Vec<String>> {
+        let hashes = hash_image_blocking(photo.to_vec(), self.hash_config, settings.robust_matching).await?;
+        if kind.has_keyframe() {
+            Some(hashes.into_iter().map(|hash| format!("{}{}", KEYFRAME_PREFIX, hash)).collect())
+        } else {
+            Some(hashes)
+        }
+    }
+
+    // Maps media to the url of the same file seen before, or for images and
+    // videos to the url of the closest image or frame seen before, or to a new
+    // url derived from its hash or file id. `hashes` are those of the photo,
+    // if they were computed already.
+    async fn media_url(&self, msg: &IncomingMessage, kind: MediaKind, hashes: Option<Vec<String>>,
+                       clean_chat_id: &str, settings: &ChatSettings) -> Option<Url> {
+        if let Some(file_unique_id) = &msg.file_unique_id {
+            if let Some(key) = find_file_id(&*self.storage, file_unique_id, clean_chat_id, settings.timeout_days) {
+                info!("Found existing file {:?} as {:?}", file_unique_id, key.url);
+                ImageMetrics::hit(&self.image_metrics.exact);
+                insert_file_id(&*self.storage, file_unique_id, clean_chat_id, &key);
+                self.log_image_metrics();
+                return Some(key.url);
+            }
+        }
+        let url = match (msg.photo.as_deref(), &msg.file_unique_id) {
+            (Some(photo), _) => {
+                let hashes = match hashes {
+                    Some(hashes) => Some(hashes),
+                    // the file expired since it was looked up
+                    None => self.hash_photo(kind, photo, settings).await,
+                };
+                self.hashed_url(msg, hashes, clean_chat_id, settings)
+            },
+            // nothing to hash, only the very same file matches
+            (None, Some(file_unique_id)) if kind != MediaKind::Photo => {
+                ImageMetrics::hit(&self.image_metrics.new);
+                Url::parse(&format!("https://file.telegram.com/{}/{}", kind.as_str(), file_unique_id)).ok()
+            },
+            _ => {
+                warn!("No exact match and nothing to hash");
+                None
+            }
+        };
+        // later re-sends of the same file take the fast path
+        if let (Some(url), Some(file_unique_id)) = (&url, &msg.file_unique_id) {
+            let key = MessageKey{chat_id: String::from(clean_chat_id), url: url.clone()};
+            insert_file_id(&*self.storage, file_unique_id, clean_chat_id, &key);
+        }
+        self.log_image_metrics();
+        url
+    }
+
+    // Maps an image, or a frame of a video, to the url of the closest one seen
+    // before, or to a new url derived from its own hash.
+    fn hashed_url(&self, msg: &IncomingMessage, hashes: Option<Vec<String>>, clean_chat_id: &str,
+                  settings: &ChatSettings) -> Option<Url> {
+        let hashes = match hashes {
+            Some(hashes) => hashes,
+            None => {
+                warn!("Failed to get hash");
+                return None;
+            }
+        };
+        let hash = hashes[0].clone();
+        trace!("Get hash {} with {} variants", &hash, hashes.len() - 1);
+        let url = match check_img_hashes(&*self.storage, &self.image_index, &hashes, clean_chat_id,
+                                         settings.threshold, settings.timeout_days) {
+            Ok(Some(key)) => {
+                info!("Found existing hash {:?} that is close", key.url);
+                ImageMetrics::hit(&self.image_metrics.perceptual);
+                Some(key.url)
+            },
+            _ => {
+                trace!("No close hash is found, use original hash {:?}", &hash);
+                ImageMetrics::hit(&self.image_metrics.new);
+                Url::parse(&format!("https://img.telegram.com/{}", hash)).ok()
+            }
+        };
+        // store the new hash result, unless an exact key exist.
+        if let Some(url) = url.clone() {
+            let key = MessageKey{chat_id: String::from(clean_chat_id), url: url.clone()};
+            for (i, hash) in hashes.iter().enumerate() {
+                if contains_img_hash(&*self.storage, hash, clean_chat_id) {
+                    continue;
+                }
+                // variants cannot be re-hashed from the file, they expire instead
+                let file_id = msg.photo_file_id.as_deref().filter(|_| i == 0);
+                if !insert_img_hash(&*self.storage, &self.image_index, hash, clean_chat_id, &key, file_id) {
+                    warn!("insert error, with hash {:?} and key {:?}", hash, &key);
+                }
+            }
+        }
+        url
+    }
+
+    /// Re-hashes the images hashed with another algorithm, see
+    /// [`image::rehash_images`](crate::image::rehash_images).
+    pub async fn rehash_images<F, Fut>(&self, fetch: F) -> RehashReport
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        let report = image::rehash_images(&*self.storage, &self.hash_config, fetch).await;
+        self.image_index.clear();
+        report
+    }
+
+    fn log_image_metrics(&self) {
+        let (exact, perceptual, new) = self.image_metrics.snapshot();
+        info!("Media so far: {} exact matches, {} perceptual matches, {} new", exact, perceptual, new);
+    }
+
+    /// Removes expired entries from all databases, see [`gc`](crate::gc). The
+    /// databases are scanned on the blocking pool, so that messages keep being
+    /// handled meanwhile.
+    pub async fn collect_garbage(&self, config: &GcConfig) -> GcReport {
+        let (storage, default_days, dry_run) = (self.storage.clone(), self.defaults.retention_days, config.dry_run);
+        let collected = tokio::task::spawn_blocking(move || {
+            let now = Utc::now();
+            let retention = Retention {
+                default_days,
+                per_chat: settings::all_settings(&*storage)
+                                    .into_iter()
+                                    .map(|(chat_id, s)| (chat_id, s.retention_days))
+                                    .collect(),
+            };
+            let (images, image_chats) = gc::collect_images(&*storage, &retention, dry_run, now);
+            let report = GcReport {
+                messages: gc::collect_messages(&*storage, &retention, dry_run, now),
+                images,
+                users: gc::collect_users(&*storage, &retention, dry_run, now),
+            };
+            (report, image_chats)
+        }).await;
+        let (report, image_chats) = match collected {
+            Ok(collected) => collected,
+            Err(e) => {
+                warn!("Garbage collection failed with error {:?}", &e);
+                return GcReport::default();
+            }
+        };
+        // removed hashes are skipped on lookup, but there is no need to keep them around
+        self.image_index.invalidate(&image_chats);
+        self.chat_locks.prune();
+        report
+    }
+
+    /// See [`top::topics`].
+    pub async fn topics(&self, chat_id: &str) -> Vec<(u32, String)> {
+        top::topics(&*self.storage, chat_id)
+    }
+
+    /// See [`top::top_board`].
+    pub async fn top_board(&self, chat_id: &str) -> Vec<(i64, String)> {
+        top::top_board(&*self.storage, chat_id)
+    }
+
+    /// See [`top::user_count`].
+    pub async fn user_count(&self, chat_id: &str, user_id: i64) -> Option<i64> {
+        top::user_count(&*self.storage, chat_id, user_id)
+    }
+
+    /// See [`top::reset_top_board`].
+    pub async fn reset_top_board(&self, chat_id: &str) -> usize {
+        top::reset_top_board(&*self.storage, chat_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use crate::message::Sender;
+
+    static LINK: &str = "https://example.com/some/long/path";
+
+    fn message(chat_id: i64, message_id: i32) -> IncomingMessage {
+        IncomingMessage {
+            chat_id,
+            message_id,
+            sender: Some(Sender { id: 42, name: String::from("someone") }),
+            text: Some(String::from(LINK)),
+            ..Default::default()
+        }
+    }
+
+    fn detector() -> Arc<DuplicateDetector> {
+        Arc::new(DuplicateDetector::new(Arc::new(MemoryStorage::default())))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_messages_are_checked_one_at_a_time_per_chat() {
+        let detector = detector();
+        let (chats, messages) = (8, 25);
+        let mut tasks = vec![];
+        for chat in 0..chats {
+            for message_id in 0..messages {
+                let detector = detector.clone();
+                tasks.push(tokio::spawn(async move {
+                    let msg = message(chat, message_id);
+                    let verdict = detector.check(&msg).await.unwrap();
+                    if let Verdict::Duplicate(duplicates) = &verdict {
+                        assert!(detector.count_duplicate(&msg, duplicates).await);
+                    }
+                    (chat, verdict)
+                }));
+            }
+        }
+
+        let mut counts: HashMap<i64, Vec<u32>> = HashMap::new();
+        for task in tasks {
+            let (chat, verdict) = task.await.unwrap();
+            let count = match verdict {
+                Verdict::New => 1,
+                Verdict::Duplicate(duplicates) => duplicates[0].count,
+                Verdict::Untracked => panic!("{} is not tracked", LINK),
+            };
+            counts.entry(chat).or_default().push(count);
+        }
+        for chat in 0..chats {
+            let mut chat_counts = counts.remove(&chat).unwrap();
+            chat_counts.sort_unstable();
+            // no two messages saw the same state of the chat
+            assert_eq!(chat_counts, (1..=messages as u32).collect::<Vec<_>>());
+            let clean_chat_id = chat.to_string();
+            assert_eq!(detector.user_count(&clean_chat_id, 42).await, Some(messages as i64 - 1));
+            let topics = detector.topics(&clean_chat_id).await;
+            assert_eq!(topics.iter().map(|(count, _)| *count).collect::<Vec<_>>(), vec![messages as u32]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_busy_chat_does_not_hold_up_the_others() {
+        let detector = detector();
+        let wait = std::time::Duration::from_secs(5);
+        let busy = detector.chat_locks.lock("1").await;
+
+        for chat in 2..10 {
+            let checked = tokio::time::timeout(wait, detector.check(&message(chat, 1))).await;
+            assert_eq!(checked.expect("held up by another chat").unwrap(), Verdict::New);
+            let top = tokio::time::timeout(wait, detector.top_board(&chat.to_string())).await;
+            assert!(top.expect("held up by another chat").is_empty());
+        }
+
+        let held = tokio::time::timeout(std::time::Duration::from_millis(100), detector.check(&message(1, 1))).await;
+        assert!(held.is_err());
+        drop(busy);
+        let checked = tokio::time::timeout(wait, detector.check(&message(1, 1))).await;
+        assert_eq!(checked.expect("lock of the chat not released").unwrap(), Verdict::New);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn no_lock_is_held_across_a_reply() {
+        let detector = detector();
+        let (checked, first_checked) = tokio::sync::oneshot::channel();
+        let (replied, reply) = tokio::sync::oneshot::channel::<()>();
+        let first = tokio::spawn({
+            let detector = detector.clone();
+            async move {
+                let verdict = detector.check(&message(1, 1)).await.unwrap();
+                checked.send(()).unwrap();
+                // like a reply to Telegram, which lasts until the next message is checked
+                reply.await.unwrap();
+                verdict
+            }
+        });
+
+        first_checked.await.unwrap();
+        let second = tokio::time::timeout(std::time::Duration::from_secs(5), detector.check(&message(1, 2))).await;
+        match second.expect("the lock of the chat is held across the reply").unwrap() {
+            Verdict::Duplicate(duplicates) => assert_eq!(duplicates[0].count, 2),
+            verdict => panic!("{:?} is not a duplicate", verdict),
+        }
+        replied.send(()).unwrap();
+        assert_eq!(first.await.unwrap(), Verdict::New);
+    }
+
+    #[tokio::test]
+    async fn locks_nobody_holds_are_pruned() {
+        let detector = detector();
+        for chat in 0..10 {
+            detector.check(&message(chat, 1)).await.unwrap();
+        }
+        let held = detector.chat_locks.lock("1").await;
+        assert_eq!(detector.chat_locks.locks().len(), 10);
+
+        detector.collect_garbage(&GcConfig::default()).await;
+        assert_eq!(detector.chat_locks.locks().keys().collect::<Vec<_>>(), vec!["1"]);
+        drop(held);
+        detector.chat_locks.prune();
+        assert!(detector.chat_locks.locks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn photos_of_an_album_are_not_duplicates_of_each_other() {
+        let detector = detector();
+        assert!(detector.check_album(&[]).await.unwrap().is_empty());
+
+        let album = [message(1, 1), message(1, 2)];
+        assert_eq!(detector.check_album(&album).await.unwrap(), vec![vec![], vec![]]);
+
+        // the earlier album is what the later one duplicates
+        let album = [message(1, 3), message(1, 4)];
+        let checked = detector.check_album(&album).await.unwrap();
+        assert_eq!(checked.len(), 2);
+        for duplicates in checked {
+            assert_eq!(duplicates.len(), 1);
+            assert_eq!(duplicates[0].count, 3);
+            let originals: Vec<_> = duplicates[0].originals.iter().map(|o| o.message_id).collect();
+            assert_eq!(originals, vec![Some(1), Some(2)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicates_count_once_an_original_is_known_to_exist() {
+        let detector = detector();
+        detector.check(&message(1, 1)).await.unwrap();
+        let duplicate = message(1, 2);
+        let duplicates = match detector.check(&duplicate).await.unwrap() {
+            Verdict::Duplicate(duplicates) => duplicates,
+            verdict => panic!("{:?} is not a duplicate", verdict),
+        };
+        assert_eq!(detector.topics("1").await[0].0, 1);
+        assert_eq!(detector.user_count("1", 42).await, None);
+
+        assert!(detector.count_duplicate(&duplicate, &duplicates).await);
+        assert_eq!(detector.topics("1").await[0].0, 2);
+        assert_eq!(detector.user_count("1", 42).await, Some(1));
+
+        // e.g. a channel post, nobody to count it against
+        let anonymous = IncomingMessage { sender: None, ..message(1, 3) };
+        assert!(detector.count_duplicate(&anonymous, &duplicates).await);
+        assert_eq!(detector.topics("1").await[0].0, 3);
+        assert_eq!(detector.top_board("1").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_occurrences_within_the_window_are_duplicates() {
+        let detector = detector();
+        let mut settings = ChatSettings::default();
+        settings.set("window_hours", "1").unwrap();
+        detector.save_settings("1", &settings).await;
+        detector.check(&message(1, 1)).await.unwrap();
+        // as if it was seen two hours ago
+        for (key, mut info) in detector.storage.messages(Some("1")).unwrap() {
+            info.history.iter_mut().for_each(|o| o.seen_at = o.seen_at - chrono::Duration::hours(2));
+            detector.storage.save_message(&key, &info).unwrap();
+        }
+
+        assert_eq!(detector.check(&message(1, 2)).await.unwrap(), Verdict::New);
+        match detector.check(&message(1, 3)).await.unwrap() {
+            Verdict::Duplicate(duplicates) => {
+                assert_eq!(duplicates[0].count, 2);
+                let originals: Vec<_> = duplicates[0].originals.iter().map(|o| o.message_id).collect();
+                assert_eq!(originals, vec![Some(2)]);
+            },
+            verdict => panic!("{:?} is not a duplicate", verdict),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_link_of_a_message_is_checked_once() {
+        let detector = detector();
+        let other = "https://example.com/other/long/path";
+        let with_links = |message_id, links: &[&str]| IncomingMessage {
+            text: Some(String::from("看看这两个")),
+            links: links.iter().map(|link| String::from(*link)).collect(),
+            ..message(1, message_id)
+        };
+        // a link repeated in the same message is not a duplicate of itself
+        assert_eq!(detector.check(&with_links(1, &[LINK, LINK])).await.unwrap(), Verdict::New);
+
+        match detector.check(&with_links(2, &[other, LINK])).await.unwrap() {
+            Verdict::Duplicate(duplicates) => {
+                let found: Vec<_> = duplicates.iter().map(|d| (d.url.as_str(), d.kind, d.count)).collect();
+                assert_eq!(found, vec![(LINK, DuplicateKind::Link, 2)]);
+            },
+            verdict => panic!("{:?} is not a duplicate", verdict),
+        }
+        assert!(matches!(detector.check(&with_links(3, &[other])).await.unwrap(), Verdict::Duplicate(_)));
+    }
+}
@@ -0,0 +1,349 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::rules::Rule;
+use crate::settings::ChatSettings;
+use crate::storage::Storage;
+use crate::store::{FileIdKey, ImageKey, ImageValue, MessageInfo, MessageKey, Occurrence, TopUserValue, UserKey};
+
+/// Version of the dump format written by [`export`]. [`import`] reads dumps of
+/// this version or older, and refuses newer ones.
+pub static DUMP_VERSION: u32 = 1;
+
+/// A line of a dump. The first line is always a header, the others come in no
+/// particular order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DumpRecord {
+    Header {
+        version: u32,
+        exported_at: DateTime<Utc>,
+        // the chat the dump is limited to, if any
+        #[serde(default)]
+        chat_id: Option<String>,
+    },
+    Message { key: MessageKey, value: MessageInfo },
+    Image { key: ImageKey, value: ImageValue },
+    File { key: FileIdKey, value: ImageValue },
+    User { key: UserKey, value: TopUserValue },
+    Settings { chat_id: String, value: ChatSettings },
+    // rules of a chat, or the global ones
+    Rules { key: String, value: Vec<Rule> },
+}
+
+/// What [`import`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    // records that were not there yet
+    pub added: usize,
+    // records merged with the one already there
+    pub merged: usize,
+    // lines that could not be read or stored, left out
+    pub failed: usize,
+}
+
+/// Writes the records of `chat_id`, or of every chat, to `out` as JSON Lines,
+/// returns how many records were written. Global rules are only part of a
+/// dump of every chat.
+pub fn export(storage: &dyn Storage, chat_id: Option<&str>, out: &mut dyn Write) -> Result<usize> {
+    let mut written = 0;
+    let mut write = |record: DumpRecord| -> Result<()> {
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+        written += 1;
+        Ok(())
+    };
+
+    write(DumpRecord::Header { version: DUMP_VERSION, exported_at: Utc::now(), chat_id: chat_id.map(String::from) })?;
+    for (key, value) in storage.messages(chat_id)? {
+        write(DumpRecord::Message { key, value })?;
+    }
+    for (key, value) in storage.images(chat_id)? {
+        write(DumpRecord::Image { key, value })?;
+    }
+    for (key, value) in storage.files(chat_id)? {
+        write(DumpRecord::File { key, value })?;
+    }
+    for (key, value) in storage.users(chat_id)? {
+        write(DumpRecord::User { key, value })?;
+    }
+    for (key, value) in storage.all_settings()? {
+        if chat_id.map_or(true, |chat_id| chat_id == key) {
+            write(DumpRecord::Settings { chat_id: key, value })?;
+        }
+    }
+    for (key, value) in storage.all_rules()? {
+        if chat_id.map_or(true, |chat_id| chat_id == key) {
+            write(DumpRecord::Rules { key, value })?;
+        }
+    }
+    out.flush()?;
+    // the header is not a record
+    Ok(written - 1)
+}
+
+/// Reads a dump written by [`export`] into `storage`. Records already in
+/// `storage` are merged rather than replaced, so that importing the same dump
+/// twice, or dumps of two deployments, loses nothing: histories are joined,
+/// counts add up the occurrences only one side saw, timestamps take the
+/// highest value, and settings and rules already there win.
+pub fn import(storage: &dyn Storage, input: &mut dyn BufRead) -> Result<ImportReport> {
+    let mut lines = input.lines();
+    let header = match lines.next() {
+        Some(line) => serde_json::from_str::<DumpRecord>(&line?)?,
+        None => return Err(anyhow!("the dump is empty")),
+    };
+    match header {
+        DumpRecord::Header { version, .. } if version > DUMP_VERSION =>
+            return Err(anyhow!("the dump is of version {}, only {} or older can be read", version, DUMP_VERSION)),
+        DumpRecord::Header { .. } => (),
+        _ => return Err(anyhow!("the dump does not start with a header")),
+    }
+
+    let mut report = ImportReport::default();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // the header is line 1
+        let line_number = i + 2;
+        let record = match serde_json::from_str::<DumpRecord>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Line {} of the dump is left out: {}", line_number, e);
+                report.failed += 1;
+                continue;
+            }
+        };
+        match import_record(storage, record) {
+            Ok(true) => report.merged += 1,
+            Ok(false) => report.added += 1,
+            Err(e) => {
+                warn!("Line {} of the dump is left out: {}", line_number, e);
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+// stores a record, tells whether there was one already
+fn import_record(storage: &dyn Storage, record: DumpRecord) -> Result<bool> {
+    let mut existed = false;
+    match record {
+        DumpRecord::Header { .. } => return Err(anyhow!("a second header")),
+        DumpRecord::Message { key, value } => {
+            storage.update_message(&key, &mut |info| {
+                existed = info.is_some();
+                Some(match info {
+                    Some(info) => merge_message(info, value.clone()),
+                    None => value.clone(),
+                })
+            })?;
+        },
+        DumpRecord::Image { key, value } => {
+            let current = storage.find_image(&key)?;
+            existed = current.is_some();
+            if current.map_or(true, |current| current.timestamp < value.timestamp) {
+                storage.save_image(&key, &value)?;
+            }
+        },
+        DumpRecord::File { key, value } => {
+            let current = storage.find_file(&key)?;
+            existed = current.is_some();
+            if current.map_or(true, |current| current.timestamp < value.timestamp) {
+                storage.save_file(&key, &value)?;
+            }
+        },
+        DumpRecord::User { key, value } => {
+            storage.update_user(&key, &mut |current| {
+                existed = current.is_some();
+                Some(match current {
+                    Some(current) => merge_user(current, value.clone()),
+                    None => value.clone(),
+                })
+            })?;
+        },
+        DumpRecord::Settings { chat_id, value } => {
+            existed = storage.find_settings(&chat_id)?.is_some();
+            if !existed {
+                storage.save_settings(&chat_id, &value)?;
+            }
+        },
+        DumpRecord::Rules { key, value } => {
+            // without rules of its own, the dumped ones are taken as they are,
+            // including defaults the source removed
+            let stored = storage.find_rules(&key)?;
+            existed = stored.is_some();
+            let mut rules = stored.unwrap_or_default();
+            for rule in value {
+                if !rules.iter().any(|r| r.pattern == rule.pattern) {
+                    rules.push(rule);
+                }
+            }
+            storage.save_rules(&key, &rules)?;
+        },
+    }
+    Ok(existed)
+}
+
+fn earliest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Occurrences both records have are counted once. A record whose history has
+// nothing new is taken as imported before, as histories may have been cut by
+// the gc since.
+fn merge_message(mut info: MessageInfo, other: MessageInfo) -> MessageInfo {
+    let same = |a: &Occurrence, b: &Occurrence| a.seen_at == b.seen_at && a.message_id == b.message_id;
+    let shared = other.history.iter().filter(|o| info.history.iter().any(|h| same(h, o))).count();
+    info.count = if shared == other.history.len() {
+        info.count.max(other.count)
+    } else {
+        info.count + other.count.saturating_sub(shared as u32)
+    };
+    info.link = info.link.or(other.link);
+    info.user_id = info.user_id.or(other.user_id);
+    info.first_seen = earliest(info.first_seen, other.first_seen);
+    info.last_seen = info.last_seen.max(other.last_seen);
+    for occurrence in other.history {
+        if !info.history.iter().any(|o| same(o, &occurrence)) {
+            info.history.push(occurrence);
+        }
+    }
+    info.history.sort_by_key(|o| o.seen_at);
+    info
+}
+
+fn merge_user(current: TopUserValue, other: TopUserValue) -> TopUserValue {
+    let count = current.count.max(other.count);
+    // the name the user had most recently
+    let (mut newer, older) = if current.last_seen >= other.last_seen { (current, other) } else { (other, current) };
+    newer.count = count;
+    newer.username = newer.username.or(older.username);
+    newer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use crate::rules::{RuleAction, GLOBAL_RULES};
+    use url::Url;
+
+    fn message_key(chat_id: &str) -> MessageKey {
+        MessageKey { chat_id: String::from(chat_id), url: Url::parse("https://example.com/a").unwrap() }
+    }
+
+    fn occurrence(message_id: i32) -> Occurrence {
+        Occurrence { seen_at: Utc::now(), link: None, user_id: Some(42), username: None,
+                     message_id: Some(message_id) }
+    }
+
+    fn dump(storage: &dyn Storage, chat_id: Option<&str>) -> Vec<u8> {
+        let mut out = Vec::new();
+        export(storage, chat_id, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn importing_twice_changes_nothing() {
+        let source = MemoryStorage::default();
+        let mut info = MessageInfo::new(message_key("1").url, occurrence(1));
+        info.record(occurrence(2));
+        source.save_message(&message_key("1"), &info).unwrap();
+        source.save_message(&message_key("2"), &info).unwrap();
+        source.save_user(&UserKey { chat_id: String::from("1"), user_id: 42 },
+                         &TopUserValue { username: None, count: 3, last_seen: None }).unwrap();
+        let out = dump(&source, Some("1"));
+
+        let target = MemoryStorage::default();
+        let report = import(&target, &mut &out[..]).unwrap();
+        assert_eq!(report, ImportReport { added: 2, merged: 0, failed: 0 });
+        let report = import(&target, &mut &out[..]).unwrap();
+        assert_eq!(report, ImportReport { added: 0, merged: 2, failed: 0 });
+
+        let imported = target.find_message(&message_key("1")).unwrap().unwrap();
+        assert_eq!(imported.count, 2);
+        assert_eq!(imported.history, info.history);
+        assert!(target.find_message(&message_key("2")).unwrap().is_none());
+    }
+
+    #[test]
+    fn histories_of_two_deployments_are_joined() {
+        let (a, b) = (MemoryStorage::default(), MemoryStorage::default());
+        // both saw the first occurrence, then one more each
+        let first = MessageInfo::new(message_key("1").url, occurrence(1));
+        let (mut in_a, mut in_b) = (first.clone(), first);
+        in_a.record(occurrence(2));
+        in_b.record(occurrence(3));
+        a.save_message(&message_key("1"), &in_a).unwrap();
+        b.save_message(&message_key("1"), &in_b).unwrap();
+
+        import(&a, &mut &dump(&b, None)[..]).unwrap();
+        let merged = a.find_message(&message_key("1")).unwrap().unwrap();
+        assert_eq!(merged.count, 3);
+        let ids: Vec<_> = merged.history.iter().map(|o| o.message_id.unwrap()).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn counts_of_unrelated_records_add_up() {
+        let (a, b) = (MemoryStorage::default(), MemoryStorage::default());
+        let mut in_a = MessageInfo::new(message_key("1").url, occurrence(1));
+        in_a.record(occurrence(2));
+        let mut in_b = MessageInfo::new(message_key("1").url, occurrence(3));
+        in_b.record(occurrence(4));
+        in_b.record(occurrence(5));
+        a.save_message(&message_key("1"), &in_a).unwrap();
+        b.save_message(&message_key("1"), &in_b).unwrap();
+
+        let out = dump(&b, None);
+        import(&a, &mut &out[..]).unwrap();
+        assert_eq!(a.find_message(&message_key("1")).unwrap().unwrap().count, 5);
+        // nothing new the second time
+        import(&a, &mut &out[..]).unwrap();
+        let merged = a.find_message(&message_key("1")).unwrap().unwrap();
+        assert_eq!(merged.count, 5);
+        assert_eq!(merged.history.len(), 5);
+    }
+
+    #[test]
+    fn newer_and_headless_dumps_are_refused() {
+        let storage = MemoryStorage::default();
+        let newer = format!("{{\"kind\":\"header\",\"version\":{},\"exported_at\":\"2021-01-01T00:00:00Z\"}}\n",
+                            DUMP_VERSION + 1);
+        assert!(import(&storage, &mut newer.as_bytes()).is_err());
+        let headless = dump(&storage, None).split(|c| *c == b'\n').skip(1).collect::<Vec<_>>().concat();
+        assert!(import(&storage, &mut &headless[..]).is_err());
+        assert!(import(&storage, &mut &b""[..]).is_err());
+    }
+
+    #[test]
+    fn bad_lines_are_counted_and_skipped() {
+        let storage = MemoryStorage::default();
+        let mut input = dump(&storage, None);
+        input.extend_from_slice(b"{\"kind\":\"message\"}\nnot json\n");
+        let report = import(&storage, &mut &input[..]).unwrap();
+        assert_eq!(report, ImportReport { added: 0, merged: 0, failed: 2 });
+    }
+
+    #[test]
+    fn dumped_rules_are_restored_as_they_are() {
+        let storage = MemoryStorage::default();
+        let mut input = dump(&storage, None);
+        let rules = vec![Rule::new("example.com", RuleAction::Ignore)];
+        let record = DumpRecord::Rules { key: String::from(GLOBAL_RULES), value: rules.clone() };
+        input.extend(serde_json::to_vec(&record).unwrap());
+        import(&storage, &mut &input[..]).unwrap();
+        assert_eq!(storage.find_rules(GLOBAL_RULES).unwrap(), Some(rules));
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::AddAssign;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::detector::DuplicateDetector;
+use crate::image::FILE_ID_TREE;
+use crate::keys::BinaryKey;
+use crate::settings::days_before;
+use crate::sled_storage::{IMAGES_TREE, MESSAGES_TREE, USERS_TREE};
+use crate::storage::Storage;
+use crate::store::{MessageInfo, MessageKey, TopUserValue, UserKey};
+
+/// How often expired entries get removed from the databases. How long entries
+/// are kept is part of each chat's settings.
+#[derive(Debug, Clone)]
+pub struct GcConfig {
+    // time between two runs
+    pub interval: std::time::Duration,
+    // only report what would be removed
+    pub dry_run: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            interval: std::time::Duration::from_secs(60 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+/// Retention of every chat, taken from the chat settings.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub default_days: i64,
+    // days, keyed by the chat id used in keys
+    pub per_chat: HashMap<String, i64>,
+}
+
+impl Retention {
+    pub fn for_chat(&self, chat_id: &str) -> i64 {
+        *self.per_chat.get(chat_id).unwrap_or(&self.default_days)
+    }
+
+    fn cutoff_for(&self, chat_id: &str, now: DateTime<Utc>) -> DateTime<Utc> {
+        days_before(now, self.for_chat(chat_id))
+    }
+}
+
+/// What was (or would be, in a dry run) reclaimed from one database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+impl AddAssign for GcStats {
+    fn add_assign(&mut self, other: Self) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub messages: GcStats,
+    pub images: GcStats,
+    pub users: GcStats,
+}
+
+impl GcReport {
+    pub fn total(&self) -> GcStats {
+        let mut total = self.messages;
+        total += self.images;
+        total += self.users;
+        total
+    }
+}
+
+// bytes taken by a record, as laid out by every storage but SQLite
+fn record_size<K: BinaryKey, V: Serialize>(key: &K, value: &V) -> usize {
+    key.to_bytes().map_or(0, |k| k.len()) + serde_json::to_vec(value).map_or(0, |v| v.len())
+}
+
+/// Removes messages not seen since the cutoff of their chat, and drops the
+/// expired part of the history of the remaining ones. Records written before
+/// timestamps were tracked are taken as seen at `now`, so that they are kept
+/// for one more retention period rather than removed at once.
+pub fn collect_messages(storage: &dyn Storage, retention: &Retention, dry_run: bool,
+                        now: DateTime<Utc>) -> GcStats {
+    let mut stats = GcStats::default();
+    let messages = match storage.messages(None) {
+        Ok(messages) => messages,
+        Err(e) => {
+            warn!("Error in listing {}, error {:?}", MESSAGES_TREE, &e);
+            return stats;
+        }
+    };
+    for (msg_key, mut info) in messages {
+        let cutoff = retention.cutoff_for(&msg_key.chat_id, now);
+        let expired = |info: &MessageInfo| info.last_seen.is_some_and(|t| t < cutoff);
+
+        if info.last_seen.is_none() {
+            backfill_message(storage, &msg_key, dry_run, now);
+            continue;
+        }
+        if expired(&info) {
+            debug!("Expired message {:?}", &msg_key);
+            stats.keys += 1;
+            stats.bytes += record_size(&msg_key, &info);
+            if !dry_run {
+                // unless seen again meanwhile
+                if let Err(e) = storage.update_message(&msg_key, &mut |info| info.filter(|info| !expired(info))) {
+                    warn!("Error in removing {:?} from {}, error {:?}", &msg_key, MESSAGES_TREE, &e);
+                }
+            }
+            continue;
+        }
+
+        let history_len = info.history.len();
+        let old_size = record_size(&msg_key, &info);
+        info.history.retain(|o| o.seen_at >= cutoff);
+        if info.history.len() < history_len {
+            stats.bytes += old_size.saturating_sub(record_size(&msg_key, &info));
+            if !dry_run {
+                let trimmed = storage.update_message(&msg_key, &mut |info| info.map(|mut info| {
+                    info.history.retain(|o| o.seen_at >= cutoff);
+                    info
+                }));
+                if let Err(e) = trimmed {
+                    warn!("Error in saving {:?} to {}, error {:?}", &msg_key, MESSAGES_TREE, &e);
+                }
+            }
+        }
+    }
+    stats
+}
+
+// gives a message without a timestamp the time of the first collection that sees it
+fn backfill_message(storage: &dyn Storage, key: &MessageKey, dry_run: bool, now: DateTime<Utc>) {
+    debug!("Message {:?} has no timestamp, taking {}", key, now);
+    if dry_run {
+        return;
+    }
+    let backfilled = storage.update_message(key, &mut |info| info.map(|mut info| {
+        info.last_seen.get_or_insert(now);
+        info
+    }));
+    if let Err(e) = backfilled {
+        warn!("Error in saving {:?} to {}, error {:?}", key, MESSAGES_TREE, &e);
+    }
+}
+
+// like backfill_message, for the top board
+fn backfill_user(storage: &dyn Storage, key: &UserKey, dry_run: bool, now: DateTime<Utc>) {
+    debug!("User {:?} has no timestamp, taking {}", key, now);
+    if dry_run {
+        return;
+    }
+    let backfilled = storage.update_user(key, &mut |value| value.map(|mut value| {
+        value.last_seen.get_or_insert(now);
+        value
+    }));
+    if let Err(e) = backfilled {
+        warn!("Error in saving {:?} to {}, error {:?}", key, USERS_TREE, &e);
+    }
+}
+
+/// Removes image hashes and file ids not matched since the cutoff of their
+/// chat. Also returns the chats some image hashes were removed from.
+pub fn collect_images(storage: &dyn Storage, retention: &Retention, dry_run: bool,
+                      now: DateTime<Utc>) -> (GcStats, HashSet<String>) {
+    let mut stats = GcStats::default();
+    let mut chats = HashSet::new();
+    match storage.images(None) {
+        Ok(images) => for (img_key, img_value) in images {
+            if img_value.timestamp < retention.cutoff_for(&img_key.chat_id, now) {
+                debug!("Expired image {:?}", &img_key);
+                stats.keys += 1;
+                stats.bytes += record_size(&img_key, &img_value);
+                if !dry_run {
+                    match storage.remove_image(&img_key) {
+                        Ok(()) => {
+                            chats.insert(img_key.chat_id.clone());
+                        },
+                        Err(e) => warn!("Error in removing {:?} from {}, error {:?}", &img_key, IMAGES_TREE, &e),
+                    }
+                }
+            }
+        },
+        Err(e) => warn!("Error in listing {}, error {:?}", IMAGES_TREE, &e),
+    }
+
+    match storage.files(None) {
+        Ok(files) => for (file_key, file_value) in files {
+            if file_value.timestamp < retention.cutoff_for(&file_key.chat_id, now) {
+                debug!("Expired file {:?}", &file_key);
+                stats.keys += 1;
+                stats.bytes += record_size(&file_key, &file_value);
+                if !dry_run {
+                    if let Err(e) = storage.remove_file(&file_key) {
+                        warn!("Error in removing {:?} from {}, error {:?}", &file_key, FILE_ID_TREE, &e);
+                    }
+                }
+            }
+        },
+        Err(e) => warn!("Error in listing {}, error {:?}", FILE_ID_TREE, &e),
+    }
+    (stats, chats)
+}
+
+/// Removes users from the top board who have not sent a duplicate since the
+/// cutoff of their chat. Users whose last duplicate is unknown are kept for
+/// one more retention period, see [`collect_messages`].
+pub fn collect_users(storage: &dyn Storage, retention: &Retention, dry_run: bool,
+                     now: DateTime<Utc>) -> GcStats {
+    let mut stats = GcStats::default();
+    let users = match storage.users(None) {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("Error in listing {}, error {:?}", USERS_TREE, &e);
+            return stats;
+        }
+    };
+    for (user_key, user_value) in users {
+        let cutoff = retention.cutoff_for(&user_key.chat_id, now);
+        let expired = |value: &TopUserValue| value.last_seen.is_some_and(|t| t < cutoff);
+
+        if user_value.last_seen.is_none() {
+            backfill_user(storage, &user_key, dry_run, now);
+            continue;
+        }
+        if expired(&user_value) {
+            debug!("Expired user {:?}", &user_key);
+            stats.keys += 1;
+            stats.bytes += record_size(&user_key, &user_value);
+            if !dry_run {
+                // unless counted again meanwhile
+                if let Err(e) = storage.update_user(&user_key, &mut |value| value.filter(|value| !expired(value))) {
+                    warn!("Error in removing {:?} from {}, error {:?}", &user_key, USERS_TREE, &e);
+                }
+            }
+        }
+    }
+    stats
+}
+
+/// Runs garbage collection on `detector` every `config.interval`, for as long
+/// as the returned task is alive.
+pub fn spawn_gc(detector: Arc<DuplicateDetector>, config: GcConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            let report = detector.collect_garbage(&config).await;
+            let total = report.total();
+            info!("{} {} keys and {} bytes: {:?}",
+                  if config.dry_run { "Dry run would reclaim" } else { "Reclaimed" },
+                  total.keys, total.bytes, &report);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use chrono::Duration;
+    use url::Url;
+
+    fn retention() -> Retention {
+        Retention { default_days: 10, per_chat: HashMap::new() }
+    }
+
+    #[test]
+    fn records_without_timestamps_expire_after_a_retention_period() {
+        let storage = MemoryStorage::default();
+        let url = Url::parse("https://example.com/a").unwrap();
+        let msg_key = MessageKey { chat_id: String::from("1"), url: url.clone() };
+        let legacy = MessageInfo { url, count: 3, link: None, user_id: None, first_seen: None, last_seen: None,
+                                   history: vec![] };
+        storage.save_message(&msg_key, &legacy).unwrap();
+        let user_key = UserKey { chat_id: String::from("1"), user_id: 42 };
+        storage.save_user(&user_key, &TopUserValue { username: None, count: 3, last_seen: None }).unwrap();
+
+        let now = Utc::now();
+        assert_eq!(collect_messages(&storage, &retention(), false, now).keys, 0);
+        assert_eq!(collect_users(&storage, &retention(), false, now).keys, 0);
+        assert_eq!(storage.find_message(&msg_key).unwrap().unwrap().last_seen, Some(now));
+        assert_eq!(storage.find_user(&user_key).unwrap().unwrap().last_seen, Some(now));
+
+        let later = now + Duration::days(11);
+        assert_eq!(collect_messages(&storage, &retention(), false, later).keys, 1);
+        assert_eq!(collect_users(&storage, &retention(), false, later).keys, 1);
+        assert!(storage.find_message(&msg_key).unwrap().is_none());
+        assert!(storage.find_user(&user_key).unwrap().is_none());
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ::image::GenericImageView;
+use img_hash::{HashAlg, ImageHash};
+use tracing::{info, warn};
+
+use crate::bktree::BkTree;
+use crate::settings::days_before;
+use crate::storage::Storage;
+use crate::store::{FileIdKey, ImageKey, ImageValue, MessageKey};
+
+pub static TIME_OUT_DAYS: i64 = 10;
+
+/// Tree that maps the `file_unique_id` of media to the message they were
+/// first seen in, see 狭义图片重复 in the PRD.
+pub static FILE_ID_TREE: &str = "file_unique_ids";
+
+/// How media got recognized since startup.
+#[derive(Debug, Default)]
+pub struct ImageMetrics {
+    // same file_unique_id as earlier media, nothing downloaded
+    pub exact: AtomicU64,
+    // close enough perceptual hash to an earlier image or frame
+    pub perceptual: AtomicU64,
+    // not seen before
+    pub new: AtomicU64,
+}
+
+impl ImageMetrics {
+    pub fn hit(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Exact, perceptual and new counts.
+    pub fn snapshot(&self) -> (u64, u64, u64) {
+        (self.exact.load(Ordering::Relaxed),
+         self.perceptual.load(Ordering::Relaxed),
+         self.new.load(Ordering::Relaxed))
+    }
+}
+
+/// Perceptual hash algorithms to choose from, see [`img_hash::HashAlg`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Gradient,
+    DoubleGradient,
+    Mean,
+    Blockhash,
+    // mean hash of the DCT of the image, a.k.a. pHash
+    Dct,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Gradient => "gradient",
+            HashAlgorithm::DoubleGradient => "doublegradient",
+            HashAlgorithm::Mean => "mean",
+            HashAlgorithm::Blockhash => "blockhash",
+            HashAlgorithm::Dct => "dct",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace(['_', '-'], "").as_str() {
+            "gradient" => Ok(HashAlgorithm::Gradient),
+            "doublegradient" => Ok(HashAlgorithm::DoubleGradient),
+            "mean" => Ok(HashAlgorithm::Mean),
+            "blockhash" => Ok(HashAlgorithm::Blockhash),
+            "dct" => Ok(HashAlgorithm::Dct),
+            _ => Err(anyhow!("unknown hash algorithm {:?}, expected one of gradient, doublegradient, mean, blockhash, dct", s)),
+        }
+    }
+}
+
+/// How images are hashed, the same for the whole deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashConfig {
+    pub algorithm: HashAlgorithm,
+    // width and height of the hash, in bits
+    pub size: u32,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        HashConfig { algorithm: HashAlgorithm::Gradient, size: 8 }
+    }
+}
+
+/// Tag of hashes stored before they were tagged, made with the defaults of
+/// `img_hash`.
+pub static LEGACY_TAG: &str = "gradient8";
+
+impl HashConfig {
+    /// Sizes a hash can have, larger ones only make hashing and comparing
+    /// slower.
+    pub const SIZES: std::ops::RangeInclusive<u32> = 4..=64;
+
+    /// Tag stored with every hash, hashes with different tags are never
+    /// compared.
+    pub fn tag(&self) -> String {
+        format!("{}{}", self.algorithm, self.size)
+    }
+
+    fn hasher(&self) -> img_hash::Hasher {
+        let config = img_hash::HasherConfig::new().hash_size(self.size, self.size);
+        let config = match self.algorithm {
+            HashAlgorithm::Gradient => config.hash_alg(HashAlg::Gradient),
+            HashAlgorithm::DoubleGradient => config.hash_alg(HashAlg::DoubleGradient),
+            HashAlgorithm::Mean => config.hash_alg(HashAlg::Mean),
+            HashAlgorithm::Blockhash => config.hash_alg(HashAlg::Blockhash),
+            HashAlgorithm::Dct => config.hash_alg(HashAlg::Mean).preproc_dct(),
+        };
+        config.to_hasher()
+    }
+}
+
+/// Prefix of the tag of hashes of video frames, so that they are never
+/// compared with photos.
+pub static KEYFRAME_PREFIX: &str = "keyframe/";
+
+/// Splits a stored hash into its tag and its base64 part.
+pub fn split_tag(hash_str: &str) -> (&str, &str) {
+    hash_str.split_once(':').unwrap_or((LEGACY_TAG, hash_str))
+}
+
+/// Computes the perceptual hash of an encoded image, as a base64 string
+/// tagged with the algorithm, e.g. `gradient8:<base64>`.
+pub fn hash_image(buf: &[u8], config: &HashConfig) -> Option<String> {
+    match ::image::load_from_memory(buf) {
+        Ok(img) => {
+            let hasher = config.hasher();
+            Some(format!("{}:{}", config.tag(), hasher.hash_image(&img).to_base64()))
+        },
+        Err(e) => {
+            warn!("Failed to parse image: {:?}", &e);
+            None
+        }
+    }
+}
+
+/// [`hash_image_variants`] if `variants`, [`hash_image`] otherwise, run on
+/// the blocking pool, as decoding and hashing a large image takes a while.
+pub async fn hash_image_blocking(buf: Vec<u8>, config: HashConfig, variants: bool) -> Option<Vec<String>> {
+    let hashed = tokio::task::spawn_blocking(move || {
+        if variants {
+            hash_image_variants(&buf, &config)
+        } else {
+            hash_image(&buf, &config).map(|hash| vec![hash])
+        }
+    }).await;
+    hashed.unwrap_or_else(|e| {
+        warn!("Failed to hash image: {:?}", &e);
+        None
+    })
+}
+
+/// Perceptual hashes of an encoded image and of its variants: rotated by a
+/// quarter, half and three quarters, mirrored both ways, and cropped to the
+/// center, so that an edited copy still has a variant close to the original.
+/// The hash of the image as it is comes first.
+pub fn hash_image_variants(buf: &[u8], config: &HashConfig) -> Option<Vec<String>> {
+    let img = match ::image::load_from_memory(buf) {
+        Ok(img) => img,
+        Err(e) => {
+            warn!("Failed to parse image: {:?}", &e);
+            return None;
+        }
+    };
+    let (width, height) = (img.width(), img.height());
+    let mut variants = vec![img.rotate90(), img.rotate180(), img.rotate270(), img.fliph(), img.flipv()];
+    for percent in CROP_PERCENTS {
+        let (w, h) = (width * percent / 100, height * percent / 100);
+        if w > 0 && h > 0 {
+            variants.push(img.crop_imm((width - w) / 2, (height - h) / 2, w, h));
+        }
+    }
+    let hasher = config.hasher();
+    let tag = config.tag();
+    let hashes = std::iter::once(&img).chain(variants.iter())
+                                      .map(|img| format!("{}:{}", tag, hasher.hash_image(img).to_base64()))
+                                      .collect();
+    Some(hashes)
+}
+
+// how much of the width and height the central crops keep
+const CROP_PERCENTS: [u32; 2] = [80, 60];
+
+fn file_id_key(chat_id: &str, file_unique_id: &str) -> FileIdKey {
+    FileIdKey{
+        chat_id: String::from(chat_id),
+        file_unique_id: String::from(file_unique_id),
+    }
+}
+
+// entries older than this are ignored, a timeout beyond what dates can hold
+// keeps everything
+fn timed_out_before(timeout_days: i64) -> DateTime<Utc> {
+    days_before(Utc::now(), timeout_days)
+}
+
+/// Remembers that the file `file_unique_id` was seen as `key`, refreshing its
+/// timestamp if it was known already.
+pub fn insert_file_id(storage: &dyn Storage, file_unique_id: &str, chat_id: &str, key: &MessageKey) -> bool {
+    let value = ImageValue{
+        message: key.clone(),
+        timestamp: Utc::now(),
+        file_id: None,
+    };
+    if storage.save_file(&file_id_key(chat_id, file_unique_id), &value).is_err() {
+        warn!("database error when saving file {:?} with value {:?}", &file_unique_id, &key);
+        false
+    } else {
+        true
+    }
+}
+
+/// The message an image with `file_unique_id` was seen as, unless that was
+/// more than `timeout_days` ago.
+pub fn find_file_id(storage: &dyn Storage, file_unique_id: &str, chat_id: &str,
+                    timeout_days: i64) -> Option<MessageKey> {
+    match storage.find_file(&file_id_key(chat_id, file_unique_id)) {
+        Ok(Some(value)) => {
+            if value.timestamp >= timed_out_before(timeout_days) {
+                Some(value.message)
+            } else {
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            warn!("database error {:?} when looking for file {:?}", &e, file_unique_id);
+            None
+        }
+    }
+}
+
+/// Stores a tagged hash as `key`, along with the `file_id` to download the
+/// image again.
+pub fn insert_img_hash(storage: &dyn Storage, index: &ImageIndex, hash: &str, chat_id: &str,
+                       key: &MessageKey, file_id: Option<&str>) -> bool {
+    let img_key = ImageKey{
+        chat_id: String::from(chat_id),
+        hash_str: String::from(hash),
+    };
+
+    let img_value = ImageValue{
+        message: key.clone(),
+        timestamp: Utc::now(),
+        file_id: file_id.map(String::from),
+    };
+
+    if storage.save_image(&img_key, &img_value).is_err() {
+        warn!("database error when saving key {:?} with value {:?}", &hash, &key);
+        return false;
+    }
+    index.insert(chat_id, hash);
+    true
+}
+
+pub fn contains_img_hash(storage: &dyn Storage, hash: &str, chat_id: &str) -> bool {
+    let img_key = ImageKey{
+        chat_id: String::from(chat_id),
+        hash_str: String::from(hash),
+    };
+
+    match storage.find_image(&img_key) {
+        Err(_) => {
+            warn!("database error when looking for key {:?}", &img_key);
+            false
+        },
+        Ok(ans) => ans.is_some()
+    }
+}
+
+// decodes the base64 part of a stored hash
+fn hash_bytes(base64: &str) -> Option<Vec<u8>> {
+    match ImageHash::<Box<[u8]>>::from_base64(base64) {
+        Ok(hash) => Some(hash.as_bytes().to_vec()),
+        Err(e) => {
+            warn!("invalid image hash {:?}: {:?}", base64, e);
+            None
+        }
+    }
+}
+
+/// Per chat and hash tag BK-tree of the stored image hashes, so that close
+/// hashes are found without going through every image of the chat. The tree
+/// of a chat is built from the storage the first time it is needed, e.g.
+/// after a restart, without holding up the other chats.
+#[derive(Default)]
+pub struct ImageIndex {
+    // per chat and tag, `None` until built; the map itself is only locked to
+    // pick a tree
+    trees: Mutex<HashMap<(String, String), Slot>>,
+}
+
+type Slot = Arc<Mutex<Option<BkTree<String>>>>;
+
+impl ImageIndex {
+    /// Hash strings in `chat_id` with the same tag as `hash` at most
+    /// `max_dist` away from it, with their distance, closest first.
+    pub fn find(&self, storage: &dyn Storage, chat_id: &str, hash: &str,
+                max_dist: u32) -> Vec<(u32, String)> {
+        let (tag, base64) = split_tag(hash);
+        let bytes = match hash_bytes(base64) {
+            Some(bytes) => bytes,
+            None => return vec![],
+        };
+        let slot = self.slot(chat_id, tag);
+        // built under the lock of the tree, so that a hash stored meanwhile
+        // is either read by the build or waits to be inserted after it
+        let mut tree = lock(&slot);
+        let tree = tree.get_or_insert_with(|| {
+            let tree = build_tree(storage, chat_id, tag);
+            info!("Built the {} image index of chat {} with {} entries", tag, chat_id, tree.len());
+            tree
+        });
+        tree.find(&bytes, max_dist)
+            .into_iter()
+            .map(|(dist, hash_str)| (dist, hash_str.clone()))
+            .collect()
+    }
+
+    /// Adds a hash that was just stored.
+    pub fn insert(&self, chat_id: &str, hash: &str) {
+        let (tag, base64) = split_tag(hash);
+        let slot = match self.trees().get(&(String::from(chat_id), String::from(tag))) {
+            Some(slot) => slot.clone(),
+            None => return,
+        };
+        let mut tree = lock(&slot);
+        // otherwise it gets picked up when the tree is built
+        if let Some(tree) = tree.as_mut() {
+            if let Some(bytes) = hash_bytes(base64) {
+                tree.insert(bytes, String::from(hash));
+            }
+        }
+    }
+
+    /// Drops the trees of `chat_ids`, e.g. after some of their stored hashes
+    /// were removed.
+    pub fn invalidate(&self, chat_ids: &HashSet<String>) {
+        self.trees().retain(|(chat_id, _), _| !chat_ids.contains(chat_id));
+    }
+
+    /// Drops every tree, e.g. after every stored hash was replaced.
+    pub fn clear(&self) {
+        self.trees().clear();
+    }
+
+    fn slot(&self, chat_id: &str, tag: &str) -> Slot {
+        self.trees().entry((String::from(chat_id), String::from(tag))).or_default().clone()
+    }
+
+    fn trees(&self) -> MutexGuard<'_, HashMap<(String, String), Slot>> {
+        // a panic elsewhere leaves the trees as consistent as they were
+        self.trees.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn lock(slot: &Slot) -> MutexGuard<'_, Option<BkTree<String>>> {
+    slot.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn build_tree(storage: &dyn Storage, chat_id: &str, tag: &str) -> BkTree<String> {
+    let mut tree = BkTree::default();
+    let images = match storage.images(Some(chat_id)) {
+        Ok(images) => images,
+        Err(e) => {
+            warn!("database error {:?} when listing the images of chat {:?}", &e, chat_id);
+            return tree;
+        }
+    };
+    for (img_key, _) in images {
+        let (key_tag, base64) = split_tag(&img_key.hash_str);
+        if key_tag != tag {
+            continue;
+        }
+        if let Some(bytes) = hash_bytes(base64) {
+            tree.insert(bytes, img_key.hash_str);
+        }
+    }
+    tree
+}
+
+// images with similarity < threshold will be considered the same, images
+// older than timeout_days are ignored, the gc module removes them
+pub fn check_img_hash(storage: &dyn Storage, index: &ImageIndex, hash: &str, chat_id: &str,
+                      similarity_threshold: u32, timeout_days: i64) -> Result<Option<MessageKey>> {
+    check_img_hashes(storage, index, &[String::from(hash)], chat_id, similarity_threshold, timeout_days)
+}
+
+/// Like [`check_img_hash`], for the variants of an image, the closest match of
+/// any of them wins.
+pub fn check_img_hashes(storage: &dyn Storage, index: &ImageIndex, hashes: &[String], chat_id: &str,
+                        similarity_threshold: u32, timeout_days: i64) -> Result<Option<MessageKey>> {
+    for hash in hashes {
+        ImageHash::<Box<[u8]>>::from_base64(split_tag(hash).1)
+            .map_err(|e| anyhow!("invalid image hash {}: {:?}", hash, e))?;
+    }
+    if similarity_threshold == 0 {
+        return Ok(None);
+    }
+    let mut candidates = vec![];
+    for hash in hashes {
+        candidates.extend(index.find(storage, chat_id, hash, similarity_threshold - 1));
+    }
+    candidates.sort_by_key(|(dist, _)| *dist);
+
+    let time_out_time = timed_out_before(timeout_days);
+    let count = candidates.len();
+    for (dist, hash_str) in candidates {
+        let img_key = ImageKey{chat_id: String::from(chat_id), hash_str};
+        let img_value = match storage.find_image(&img_key) {
+            Ok(Some(value)) => value,
+            // removed since the index was built
+            _ => continue,
+        };
+        // skip items too old
+        if img_value.timestamp < time_out_time {
+            continue;
+        }
+        info!("The best distance is {} among {} close entries", dist, count);
+        // the best match should update its timestamp, so it does not expire
+        touch_image(storage, &img_key, img_value.clone());
+        info!("Use this hash! {:?} with url {:?}", &img_key.hash_str, &img_value.message);
+        return Ok(Some(img_value.message));
+    }
+    Ok(None)
+}
+
+fn touch_image(storage: &dyn Storage, img_key: &ImageKey, mut value: ImageValue) -> bool {
+    value.timestamp = Utc::now();
+    match storage.save_image(img_key, &value) {
+        Ok(_) => {info!("Timestamp updated for {:?}", &img_key.hash_str)},
+        Err(_) => {warn!("Timestamp update failed for {:?}", &img_key.hash_str)}
+    };
+    true
+}
+
+// splits the tag of a stored hash into e.g. `keyframe/` and the algorithm
+fn algorithm_tag(hash_str: &str) -> (&str, &str) {
+    let tag = split_tag(hash_str).0;
+    match tag.rfind('/') {
+        Some(i) => tag.split_at(i + 1),
+        None => ("", tag),
+    }
+}
+
+/// What [`rehash_images`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RehashReport {
+    pub rehashed: usize,
+    // no file id to download them again, left as they are
+    pub skipped: usize,
+    // download or hashing failed, left as they are
+    pub failed: usize,
+}
+
+/// Re-hashes every stored image whose hash was made with another
+/// algorithm than `config`, downloading it again with `fetch` from the file id
+/// stored along with it. Images without one are left alone, they are never
+/// compared with the new hashes and expire as usual. Meant to run while the
+/// bot is stopped.
+pub async fn rehash_images<F, Fut>(storage: &dyn Storage, config: &HashConfig, fetch: F) -> RehashReport
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Option<Vec<u8>>>,
+{
+    let tag = config.tag();
+    let outdated: Vec<(ImageKey, ImageValue)> = match storage.images(None) {
+        Ok(images) => images.into_iter()
+                            .filter(|(key, _)| algorithm_tag(&key.hash_str).1 != tag)
+                            .collect(),
+        Err(e) => {
+            warn!("database error {:?} when listing images to re-hash", &e);
+            return RehashReport::default();
+        }
+    };
+    info!("{} images to re-hash with {}", outdated.len(), &tag);
+
+    let mut report = RehashReport::default();
+    for (old_key, value) in outdated {
+        let file_id = match &value.file_id {
+            Some(file_id) => file_id.clone(),
+            None => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        let prefix = algorithm_tag(&old_key.hash_str).0;
+        let hash = match fetch(file_id).await {
+            Some(buf) => hash_image_blocking(buf, *config, false).await.and_then(|hashes| hashes.into_iter().next()),
+            None => None,
+        };
+        let hash = match hash {
+            Some(hash) => format!("{}{}", prefix, hash),
+            None => {
+                warn!("Failed to re-hash {:?}", &old_key);
+                report.failed += 1;
+                continue;
+            }
+        };
+        let new_key = ImageKey{chat_id: old_key.chat_id.clone(), hash_str: hash};
+        if storage.save_image(&new_key, &value).is_err() || storage.remove_image(&old_key).is_err() {
+            warn!("database error when replacing {:?} with {:?}", &old_key, &new_key);
+            report.failed += 1;
+        } else {
+            report.rehashed += 1;
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use ::image::{DynamicImage, ImageBuffer, ImageOutputFormat, Rgb};
+    use std::sync::mpsc;
+    use url::Url;
+
+    // a PNG with a pattern that hashes differently from its rotations
+    fn png() -> Vec<u8> {
+        let img = ImageBuffer::from_fn(64, 48, |x, y| Rgb([(x * 4) as u8, (y * 5) as u8, ((x * y) % 256) as u8]));
+        encode(&DynamicImage::ImageRgb8(img))
+    }
+
+    fn encode(img: &DynamicImage) -> Vec<u8> {
+        let mut buf = vec![];
+        img.write_to(&mut buf, ImageOutputFormat::Png).unwrap();
+        buf
+    }
+
+    fn message_key(chat_id: &str) -> MessageKey {
+        MessageKey { chat_id: String::from(chat_id), url: Url::parse("https://img.telegram.com/a").unwrap() }
+    }
+
+    #[test]
+    fn a_build_in_one_chat_does_not_hold_up_another() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let index = Arc::new(ImageIndex::default());
+        let hash = hash_image(&png(), &HashConfig::default()).unwrap();
+        assert!(insert_img_hash(&*storage, &index, &hash, "2", &message_key("2"), None));
+
+        // as if the tree of chat 1 were being built
+        let slot = index.slot("1", split_tag(&hash).0);
+        let building = lock(&slot);
+        let (found, receiver) = mpsc::channel();
+        std::thread::spawn({
+            let (storage, index, hash) = (storage.clone(), index.clone(), hash.clone());
+            move || found.send(index.find(&*storage, "2", &hash, 0)).unwrap()
+        });
+        let found = receiver.recv_timeout(std::time::Duration::from_secs(5)).expect("held up by another chat");
+        assert_eq!(found, vec![(0, hash)]);
+        drop(building);
+    }
+
+    #[test]
+    fn hashes_stored_after_a_build_are_found() {
+        let storage = MemoryStorage::default();
+        let index = ImageIndex::default();
+        let hash = hash_image(&png(), &HashConfig::default()).unwrap();
+        assert!(index.find(&storage, "1", &hash, 0).is_empty());
+        insert_img_hash(&storage, &index, &hash, "1", &message_key("1"), None);
+        assert_eq!(index.find(&storage, "1", &hash, 0), vec![(0, hash.clone())]);
+
+        // the tree of another chat is left alone
+        index.find(&storage, "2", &hash, 0);
+        index.invalidate(&HashSet::from([String::from("1")]));
+        assert_eq!(index.trees().len(), 1);
+        assert_eq!(index.find(&storage, "1", &hash, 0), vec![(0, hash)]);
+    }
+
+    #[test]
+    fn variants_come_after_the_image_itself() {
+        let config = HashConfig { algorithm: HashAlgorithm::Mean, size: 16 };
+        let hashes = hash_image_variants(&png(), &config).unwrap();
+        // four turns and flips, two crops
+        assert_eq!(hashes.len(), 8);
+        assert_eq!(hashes[0], hash_image(&png(), &config).unwrap());
+        assert!(hashes.iter().all(|hash| hash.starts_with("mean16:")));
+        assert_eq!(hash_image_variants(b"not an image", &config), None);
+    }
+
+    #[test]
+    fn a_rotated_copy_is_matched_by_its_variants() {
+        let (storage, index, config) = (MemoryStorage::default(), ImageIndex::default(), HashConfig::default());
+        let hash = hash_image(&png(), &config).unwrap();
+        insert_img_hash(&storage, &index, &hash, "1", &message_key("1"), None);
+
+        let rotated = encode(&::image::load_from_memory(&png()).unwrap().rotate90());
+        let plain = hash_image(&rotated, &config).unwrap();
+        assert_eq!(check_img_hash(&storage, &index, &plain, "1", 4, TIME_OUT_DAYS).unwrap(), None);
+        let variants = hash_image_variants(&rotated, &config).unwrap();
+        let found = check_img_hashes(&storage, &index, &variants, "1", 4, TIME_OUT_DAYS).unwrap();
+        assert_eq!(found, Some(message_key("1")));
+        // a threshold of 0 turns perceptual matching off
+        assert_eq!(check_img_hashes(&storage, &index, &variants, "1", 0, TIME_OUT_DAYS).unwrap(), None);
+        assert!(check_img_hash(&storage, &index, "gradient8:not base64!", "1", 4, TIME_OUT_DAYS).is_err());
+    }
+
+    #[test]
+    fn hashes_made_with_another_config_are_not_compared() {
+        let (storage, index) = (MemoryStorage::default(), ImageIndex::default());
+        let mean = HashConfig { algorithm: HashAlgorithm::Mean, size: 8 };
+        let hash = hash_image(&png(), &mean).unwrap();
+        insert_img_hash(&storage, &index, &hash, "1", &message_key("1"), None);
+
+        let gradient = hash_image(&png(), &HashConfig::default()).unwrap();
+        assert_eq!(check_img_hash(&storage, &index, &gradient, "1", 64, TIME_OUT_DAYS).unwrap(), None);
+        assert_eq!(check_img_hash(&storage, &index, &hash, "1", 1, TIME_OUT_DAYS).unwrap(), Some(message_key("1")));
+    }
+
+    #[tokio::test]
+    async fn images_are_rehashed_from_their_file_ids() {
+        let storage = MemoryStorage::default();
+        let stored = [("gradient8:a", Some("ok")), ("keyframe/gradient8:b", Some("ok")),
+                      ("gradient8:c", Some("gone")), ("gradient8:d", None), ("mean8:e", Some("ok"))];
+        for (hash_str, file_id) in stored {
+            let key = ImageKey { chat_id: String::from("1"), hash_str: String::from(hash_str) };
+            let value = ImageValue { message: message_key("1"), timestamp: Utc::now(), file_id: file_id.map(String::from) };
+            storage.save_image(&key, &value).unwrap();
+        }
+
+        let config = HashConfig { algorithm: HashAlgorithm::Mean, size: 8 };
+        let fetch = |file_id: String| async move { Some(png()).filter(|_| file_id == "ok") };
+        let report = rehash_images(&storage, &config, fetch).await;
+        assert_eq!(report, RehashReport { rehashed: 2, skipped: 1, failed: 1 });
+
+        let hash = hash_image(&png(), &config).unwrap();
+        let mut hashes: Vec<String> = storage.images(None).unwrap().into_iter().map(|(key, _)| key.hash_str).collect();
+        hashes.sort();
+        let mut expected = vec![String::from("gradient8:c"), String::from("gradient8:d"), String::from("mean8:e"),
+                                hash.clone(), format!("{}{}", KEYFRAME_PREFIX, hash)];
+        expected.sort();
+        assert_eq!(hashes, expected);
+    }
+}
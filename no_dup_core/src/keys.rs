@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use sled::transaction::TransactionError;
+use tracing::warn;
+use url::Url;
+
+use crate::store::{FileIdKey, ImageKey, MessageKey, UserKey};
+
+/// Version of the key layout, stored in every key after its type tag.
+pub const KEY_VERSION: u8 = 1;
+
+// chat id, type tag and version
+const HEADER_LEN: usize = 8 + 1 + 1;
+
+/// A key that has no binary form, as its chat id is not a number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyError {
+    pub chat_id: String,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "chat id {:?} is not a number", self.chat_id)
+    }
+}
+
+impl Error for KeyError {}
+
+/// Keys of the databases in a binary layout: the chat id as a big-endian i64,
+/// a tag telling the type of the key, [`KEY_VERSION`], then the payload of the
+/// type. All keys of a type in a chat share [`BinaryKey::prefix`], so prefix
+/// scans return exactly those.
+pub trait BinaryKey: Sized {
+    const TAG: u8;
+
+    fn chat_id(&self) -> &str;
+    fn payload(&self) -> Vec<u8>;
+    fn from_parts(chat_id: String, payload: &[u8]) -> Option<Self>;
+
+    /// The prefix shared by the keys of this type in `chat_id`.
+    fn prefix(chat_id: &str) -> Result<Vec<u8>, KeyError> {
+        let mut prefix = Vec::with_capacity(HEADER_LEN);
+        prefix.extend_from_slice(&chat_bytes(chat_id)?);
+        prefix.push(Self::TAG);
+        prefix.push(KEY_VERSION);
+        Ok(prefix)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, KeyError> {
+        let mut key = Self::prefix(self.chat_id())?;
+        key.extend(self.payload());
+        Ok(key)
+    }
+
+    /// Decodes a key, `None` if it is of another type or version.
+    fn from_bytes(key: &[u8]) -> Option<Self> {
+        if key.len() < HEADER_LEN || key[8] != Self::TAG || key[9] != KEY_VERSION {
+            return None;
+        }
+        let mut chat_id = [0; 8];
+        chat_id.copy_from_slice(&key[..8]);
+        Self::from_parts(i64::from_be_bytes(chat_id).to_string(), &key[HEADER_LEN..])
+    }
+}
+
+// chat ids are the numeric ids from `clean_chat_id`
+fn chat_bytes(chat_id: &str) -> Result<[u8; 8], KeyError> {
+    chat_id.parse::<i64>()
+           .map(i64::to_be_bytes)
+           .map_err(|_| KeyError { chat_id: String::from(chat_id) })
+}
+
+impl BinaryKey for MessageKey {
+    const TAG: u8 = b'm';
+
+    fn chat_id(&self) -> &str {
+        &self.chat_id
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        self.url.as_str().as_bytes().to_vec()
+    }
+
+    fn from_parts(chat_id: String, payload: &[u8]) -> Option<Self> {
+        let url = Url::parse(std::str::from_utf8(payload).ok()?).ok()?;
+        Some(MessageKey { chat_id, url })
+    }
+}
+
+impl BinaryKey for ImageKey {
+    const TAG: u8 = b'i';
+
+    fn chat_id(&self) -> &str {
+        &self.chat_id
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        self.hash_str.as_bytes().to_vec()
+    }
+
+    fn from_parts(chat_id: String, payload: &[u8]) -> Option<Self> {
+        let hash_str = String::from_utf8(payload.to_vec()).ok()?;
+        Some(ImageKey { chat_id, hash_str })
+    }
+}
+
+impl BinaryKey for FileIdKey {
+    const TAG: u8 = b'f';
+
+    fn chat_id(&self) -> &str {
+        &self.chat_id
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        self.file_unique_id.as_bytes().to_vec()
+    }
+
+    fn from_parts(chat_id: String, payload: &[u8]) -> Option<Self> {
+        let file_unique_id = String::from_utf8(payload.to_vec()).ok()?;
+        Some(FileIdKey { chat_id, file_unique_id })
+    }
+}
+
+impl BinaryKey for UserKey {
+    const TAG: u8 = b'u';
+
+    fn chat_id(&self) -> &str {
+        &self.chat_id
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        self.user_id.to_be_bytes().to_vec()
+    }
+
+    fn from_parts(chat_id: String, payload: &[u8]) -> Option<Self> {
+        let mut user_id = [0; 8];
+        if payload.len() != user_id.len() {
+            return None;
+        }
+        user_id.copy_from_slice(payload);
+        Some(UserKey { chat_id, user_id: i64::from_be_bytes(user_id) })
+    }
+}
+
+// JSON keys of the layout before `BinaryKey` start with this, binary keys
+// never do, as chat ids are far from that large
+const LEGACY_PREFIX: &[u8] = b"{";
+
+// `None` if the key is not of type `K`, an error if it has no binary form
+fn from_legacy<K: BinaryKey + DeserializeOwned>(key: &[u8]) -> Option<Result<Vec<u8>>> {
+    let key = serde_json::from_slice::<K>(key).ok()?;
+    Some(key.to_bytes().map_err(anyhow::Error::from))
+}
+
+// the keys have distinct fields, so only the right type decodes
+fn convert_legacy(key: &[u8]) -> Option<Result<Vec<u8>>> {
+    from_legacy::<MessageKey>(key)
+        .or_else(|| from_legacy::<ImageKey>(key))
+        .or_else(|| from_legacy::<FileIdKey>(key))
+        .or_else(|| from_legacy::<UserKey>(key))
+}
+
+/// What [`migrate_db`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    // records copied over from the directories of the old layout, see
+    // `layout::migrate`
+    pub copied: usize,
+    pub migrated: usize,
+    // in the binary layout already
+    pub current: usize,
+    // not a key of any known type, left as it is
+    pub failed: usize,
+}
+
+/// Rewrites the JSON keys of every tree of `db` into the binary layout, in
+/// place. Every key is converted before any is rewritten, so a key that cannot
+/// be read or converted fails the migration and leaves `db` as it was. Each key
+/// is then replaced in a transaction, so an interrupted migration can simply be
+/// run again.
+pub fn migrate_db(db: &sled::Db) -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+    let mut converted = Vec::new();
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        let mut legacy = 0;
+        // collected first, as converted keys may sort after the legacy ones
+        for entry in tree.scan_prefix(LEGACY_PREFIX) {
+            let (key, value) = entry?;
+            legacy += 1;
+            match convert_legacy(&key) {
+                Some(new_key) => {
+                    let new_key = new_key.map_err(|e| {
+                        anyhow!("cannot migrate key {:?}: {}", String::from_utf8_lossy(&key), e)
+                    })?;
+                    converted.push((tree.clone(), key, new_key, value));
+                },
+                None => {
+                    warn!("Unknown key {:?}, left as it is", String::from_utf8_lossy(&key));
+                    report.failed += 1;
+                }
+            }
+        }
+        report.current += tree.len() - legacy;
+    }
+    for (tree, key, new_key, value) in converted {
+        tree.transaction::<_, _, sled::Error>(|tx| {
+            tx.insert(new_key.as_slice(), value.clone())?;
+            tx.remove(key.clone())?;
+            Ok(())
+        }).map_err(|e| match e {
+            TransactionError::Abort(e) | TransactionError::Storage(e) => e,
+        })?;
+        report.migrated += 1;
+    }
+    db.flush()?;
+    Ok(report)
+}
+
+/// Whether any tree of `db` still has keys of the old layout.
+pub fn needs_migration(db: &sled::Db) -> bool {
+    db.tree_names()
+      .iter()
+      .filter_map(|name| db.open_tree(name).ok())
+      .any(|tree| tree.scan_prefix(LEGACY_PREFIX).next().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sled_storage::{SledStorage, MESSAGES_TREE, USERS_TREE};
+    use crate::storage::{Storage, StorageError};
+    use crate::store::{MessageInfo, Occurrence, TopUserValue};
+    use chrono::Utc;
+
+    fn legacy_db(chat_id: &str) -> (sled::Db, MessageKey, UserKey) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let msg_key = MessageKey { chat_id: String::from(chat_id), url: Url::parse("https://example.com/a").unwrap() };
+        let info = MessageInfo::new(msg_key.url.clone(), Occurrence {
+            seen_at: Utc::now(), link: None, user_id: Some(42), username: None, message_id: Some(1),
+        });
+        db.open_tree(MESSAGES_TREE).unwrap()
+          .insert(serde_json::to_vec(&msg_key).unwrap(), serde_json::to_vec(&info).unwrap()).unwrap();
+        let user_key = UserKey { chat_id: String::from(chat_id), user_id: 42 };
+        let value = TopUserValue { username: Some(String::from("someone")), count: 3, last_seen: None };
+        db.open_tree(USERS_TREE).unwrap()
+          .insert(serde_json::to_vec(&user_key).unwrap(), serde_json::to_vec(&value).unwrap()).unwrap();
+        (db, msg_key, user_key)
+    }
+
+    #[test]
+    fn legacy_keys_read_back_after_the_migration() {
+        let (db, msg_key, user_key) = legacy_db("-1001");
+        assert!(needs_migration(&db));
+
+        let report = migrate_db(&db).unwrap();
+        assert_eq!(report, MigrationReport { copied: 0, migrated: 2, current: 0, failed: 0 });
+        assert!(!needs_migration(&db));
+        let storage = SledStorage::new(&db).unwrap();
+        assert_eq!(storage.find_message(&msg_key).unwrap().unwrap().count, 1);
+        assert_eq!(storage.find_user(&user_key).unwrap().unwrap().count, 3);
+        assert_eq!(storage.messages(Some("-1001")).unwrap().len(), 1);
+
+        // nothing left to do
+        assert_eq!(migrate_db(&db).unwrap(), MigrationReport { copied: 0, migrated: 0, current: 2, failed: 0 });
+    }
+
+    #[test]
+    fn a_chat_id_that_is_not_a_number_fails_the_migration() {
+        let (db, _, _) = legacy_db("somechat");
+        let error = migrate_db(&db).unwrap_err().to_string();
+        assert!(error.contains("\"somechat\" is not a number"), "{}", error);
+        // left as it was
+        assert_eq!(db.open_tree(MESSAGES_TREE).unwrap().scan_prefix(LEGACY_PREFIX).count(), 1);
+        assert_eq!(db.open_tree(USERS_TREE).unwrap().scan_prefix(LEGACY_PREFIX).count(), 1);
+    }
+
+    #[test]
+    fn keys_of_a_chat_id_that_is_not_a_number_are_refused() {
+        let key = MessageKey { chat_id: String::from("somechat"), url: Url::parse("https://example.com/a").unwrap() };
+        assert_eq!(key.to_bytes(), Err(KeyError { chat_id: String::from("somechat") }));
+        assert_eq!(UserKey::prefix("somechat"), Err(KeyError { chat_id: String::from("somechat") }));
+
+        // rather than mixed up with the records of another chat
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = SledStorage::new(&db).unwrap();
+        let info = MessageInfo::new(key.url.clone(), Occurrence {
+            seen_at: Utc::now(), link: None, user_id: None, username: None, message_id: None,
+        });
+        assert!(matches!(storage.save_message(&key, &info), Err(StorageError::Key(_))));
+        assert!(matches!(storage.messages(Some("somechat")), Err(StorageError::Key(_))));
+        assert!(storage.messages(None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tracing::info;
+
+use crate::image::FILE_ID_TREE;
+use crate::keys::{self, MigrationReport};
+use crate::sled_storage::{IMAGES_TREE, MESSAGES_TREE, RULES_TREE, SETTINGS_TREE, USERS_TREE};
+
+// trees of an old directory and the tree each goes to, `None` is the default tree
+type TreeMap = &'static [(Option<&'static str>, &'static str)];
+
+/// Directories of the layout before everything moved into the trees of a
+/// single database.
+static OLD_LAYOUT: &[(&str, TreeMap)] = &[
+    ("bot_db", &[(None, MESSAGES_TREE)]),
+    ("img_db", &[(None, IMAGES_TREE), (Some(FILE_ID_TREE), FILE_ID_TREE)]),
+    ("top_db", &[(None, USERS_TREE)]),
+    ("settings_db", &[(None, SETTINGS_TREE)]),
+    ("rules_db", &[(None, RULES_TREE)]),
+];
+
+// the old directories are looked for next to the database
+fn old_dirs(path: &str) -> impl Iterator<Item = (PathBuf, TreeMap)> {
+    let parent = Path::new(path).parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    OLD_LAYOUT.iter()
+              .map(move |(name, trees)| (parent.join(name), *trees))
+              .filter(|(dir, _)| dir.exists())
+}
+
+/// Whether directories of the old layout are left next to the database at
+/// `path`.
+pub fn has_old_layout(path: &str) -> bool {
+    old_dirs(path).next().is_some()
+}
+
+/// Copies the directories of the old layout next to `path` into the trees of
+/// the database at `path`, then converts its keys, see
+/// [`keys::migrate_db`]. Each directory is renamed to `<name>.migrated` once
+/// copied, so running it again picks up where it stopped. Meant to run while
+/// the bot is stopped.
+pub fn migrate(path: &str) -> Result<MigrationReport> {
+    let db = sled::open(path)?;
+    let mut copied = 0;
+    for (dir, trees) in old_dirs(path) {
+        {
+            let old = sled::open(&dir)?;
+            for (from, to) in trees {
+                let from = match from {
+                    Some(name) => old.open_tree(name)?,
+                    None => (*old).clone(),
+                };
+                let to = db.open_tree(to)?;
+                for entry in from.iter() {
+                    let (key, value) = entry?;
+                    to.insert(key, value)?;
+                    copied += 1;
+                }
+            }
+        }
+        db.flush()?;
+        fs::rename(&dir, dir.with_extension("migrated"))?;
+        info!("Copied {} into {}", dir.display(), path);
+    }
+    let report = MigrationReport { copied, ..keys::migrate_db(&db)? };
+    info!("Migration: {:?}", &report);
+    Ok(report)
+}
@@ -0,0 +1,40 @@
+//! Duplicate detection engine behind no_dup_bot.
+//!
+//! Nothing in here knows about Telegram's client libraries: a transport
+//! adapter turns whatever it receives into an [`IncomingMessage`], hands it to
+//! a [`DuplicateDetector`] and acts on the returned [`Verdict`].
+
+pub mod bktree;
+pub mod canonical;
+pub mod detector;
+pub mod dump;
+pub mod gc;
+pub mod image;
+pub mod keys;
+pub mod layout;
+pub mod memory_storage;
+pub mod message;
+pub mod rules;
+pub mod settings;
+pub mod sled_storage;
+pub mod sqlite_storage;
+pub mod storage;
+pub mod store;
+pub mod template;
+pub mod top;
+pub mod url_filter;
+
+pub use canonical::{Canonicalizer, SiteRule};
+pub use detector::{Duplicate, DuplicateDetector, DuplicateKind, Verdict};
+pub use dump::{DumpRecord, ImportReport, DUMP_VERSION};
+pub use gc::{spawn_gc, GcConfig, GcReport};
+pub use image::{HashAlgorithm, HashConfig, RehashReport};
+pub use keys::{BinaryKey, KeyError, MigrationReport};
+pub use memory_storage::MemoryStorage;
+pub use message::{clean_chat_id, ForwardOrigin, IncomingMessage, MediaKind, Sender};
+pub use rules::{Rule, RuleAction, RuleSet, GLOBAL_RULES};
+pub use settings::ChatSettings;
+pub use sled_storage::SledStorage;
+pub use sqlite_storage::SqliteStorage;
+pub use storage::{Quarantined, Storage, StorageConfig, StorageError, StorageResult, Update};
+pub use store::{FileIdKey, ImageKey, ImageValue, MessageInfo, MessageKey, Occurrence, TopUserValue, UserKey};
@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::image::FILE_ID_TREE;
+use crate::keys::BinaryKey;
+use crate::rules::Rule;
+use crate::settings::ChatSettings;
+use crate::sled_storage::{IMAGES_TREE, MESSAGES_TREE, RULES_TREE, SETTINGS_TREE, USERS_TREE};
+use crate::storage::{counted_user, Quarantined, Storage, StorageError, StorageResult, Update};
+use crate::store::{FileIdKey, ImageKey, ImageValue, MessageInfo, MessageKey, TopUserValue, UserKey};
+
+type Table = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+struct Tables {
+    // named like the trees of a sled database
+    tables: HashMap<&'static str, Table>,
+    quarantine: BTreeMap<(String, Vec<u8>), Vec<u8>>,
+}
+
+impl Tables {
+    fn table(&mut self, name: &'static str) -> &mut Table {
+        self.tables.entry(name).or_default()
+    }
+
+    fn get<V: DeserializeOwned>(&mut self, name: &'static str, key: &[u8]) -> StorageResult<Option<V>> {
+        match self.table(name).get(key) {
+            Some(value) => {
+                let value = value.clone();
+                Ok(Some(self.decode(name, key, &value)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn put<V: Serialize + ?Sized>(&mut self, name: &'static str, key: Vec<u8>, value: &V) -> StorageResult<()> {
+        let value = serde_json::to_vec(value)?;
+        self.table(name).insert(key, value);
+        Ok(())
+    }
+
+    fn update<V: Serialize + DeserializeOwned>(&mut self, name: &'static str, key: Vec<u8>,
+                                               f: Update<'_, V>) -> StorageResult<Option<V>> {
+        let updated = f(self.get(name, &key)?);
+        match &updated {
+            Some(value) => self.put(name, key, value)?,
+            None => self.remove(name, &key)?,
+        }
+        Ok(updated)
+    }
+
+    fn remove(&mut self, name: &'static str, key: &[u8]) -> StorageResult<()> {
+        self.table(name).remove(key);
+        Ok(())
+    }
+
+    fn scan<K: BinaryKey, V: DeserializeOwned>(&mut self, name: &'static str, chat_id: Option<&str>)
+                                                -> StorageResult<Vec<(K, V)>> {
+        let prefix = chat_id.map(K::prefix).transpose()?.unwrap_or_default();
+        let raw: Vec<(Vec<u8>, Vec<u8>)> = self.table(name)
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let mut records = vec![];
+        for (key, value) in raw {
+            let record_key = match K::from_bytes(&key) {
+                Some(record_key) => record_key,
+                None => continue,
+            };
+            // the others are still worth listing
+            if let Ok(value) = self.decode(name, &key, &value) {
+                records.push((record_key, value));
+            }
+        }
+        Ok(records)
+    }
+
+    // every record of a table keyed by text
+    fn all<V: DeserializeOwned>(&mut self, name: &'static str) -> StorageResult<Vec<(String, V)>> {
+        let raw: Vec<(Vec<u8>, Vec<u8>)> = self.table(name).clone().into_iter().collect();
+        let mut all = vec![];
+        for (key, value) in raw {
+            if let Ok(value) = self.decode(name, &key, &value) {
+                all.push((String::from_utf8_lossy(&key).into_owned(), value));
+            }
+        }
+        Ok(all)
+    }
+
+    fn decode<V: DeserializeOwned>(&mut self, name: &'static str, key: &[u8], value: &[u8]) -> StorageResult<V> {
+        serde_json::from_slice(value).map_err(|e| self.quarantine(name, key, e))
+    }
+
+    // moves an undecodable record out of its table, so that it no longer gets
+    // in the way of the others
+    fn quarantine(&mut self, name: &'static str, key: &[u8], e: serde_json::Error) -> StorageError {
+        warn!("Moving corrupt record {} in {} to the quarantine: {}", key.escape_ascii(), name, e);
+        if let Some(value) = self.table(name).remove(key) {
+            self.quarantine.insert((String::from(name), key.to_vec()), value);
+        }
+        StorageError::Corrupt { table: String::from(name), key: key.to_vec() }
+    }
+}
+
+/// [`Storage`] that keeps everything in memory, laid out like
+/// [`SledStorage`](crate::sled_storage::SledStorage). Meant for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryStorage {
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        // a panic elsewhere leaves the maps as consistent as they were
+        self.tables.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn find_message(&self, key: &MessageKey) -> StorageResult<Option<MessageInfo>> {
+        self.tables().get(MESSAGES_TREE, &key.to_bytes()?)
+    }
+
+    fn save_message(&self, key: &MessageKey, info: &MessageInfo) -> StorageResult<()> {
+        self.tables().put(MESSAGES_TREE, key.to_bytes()?, info)
+    }
+
+    fn remove_message(&self, key: &MessageKey) -> StorageResult<()> {
+        self.tables().remove(MESSAGES_TREE, &key.to_bytes()?)
+    }
+
+    fn messages(&self, chat_id: Option<&str>) -> StorageResult<Vec<(MessageKey, MessageInfo)>> {
+        self.tables().scan(MESSAGES_TREE, chat_id)
+    }
+
+    fn update_message(&self, key: &MessageKey, f: Update<'_, MessageInfo>) -> StorageResult<Option<MessageInfo>> {
+        self.tables().update(MESSAGES_TREE, key.to_bytes()?, f)
+    }
+
+    fn find_image(&self, key: &ImageKey) -> StorageResult<Option<ImageValue>> {
+        self.tables().get(IMAGES_TREE, &key.to_bytes()?)
+    }
+
+    fn save_image(&self, key: &ImageKey, value: &ImageValue) -> StorageResult<()> {
+        self.tables().put(IMAGES_TREE, key.to_bytes()?, value)
+    }
+
+    fn remove_image(&self, key: &ImageKey) -> StorageResult<()> {
+        self.tables().remove(IMAGES_TREE, &key.to_bytes()?)
+    }
+
+    fn images(&self, chat_id: Option<&str>) -> StorageResult<Vec<(ImageKey, ImageValue)>> {
+        self.tables().scan(IMAGES_TREE, chat_id)
+    }
+
+    fn find_file(&self, key: &FileIdKey) -> StorageResult<Option<ImageValue>> {
+        self.tables().get(FILE_ID_TREE, &key.to_bytes()?)
+    }
+
+    fn save_file(&self, key: &FileIdKey, value: &ImageValue) -> StorageResult<()> {
+        self.tables().put(FILE_ID_TREE, key.to_bytes()?, value)
+    }
+
+    fn remove_file(&self, key: &FileIdKey) -> StorageResult<()> {
+        self.tables().remove(FILE_ID_TREE, &key.to_bytes()?)
+    }
+
+    fn files(&self, chat_id: Option<&str>) -> StorageResult<Vec<(FileIdKey, ImageValue)>> {
+        self.tables().scan(FILE_ID_TREE, chat_id)
+    }
+
+    fn find_user(&self, key: &UserKey) -> StorageResult<Option<TopUserValue>> {
+        self.tables().get(USERS_TREE, &key.to_bytes()?)
+    }
+
+    fn save_user(&self, key: &UserKey, value: &TopUserValue) -> StorageResult<()> {
+        self.tables().put(USERS_TREE, key.to_bytes()?, value)
+    }
+
+    fn remove_user(&self, key: &UserKey) -> StorageResult<()> {
+        self.tables().remove(USERS_TREE, &key.to_bytes()?)
+    }
+
+    fn users(&self, chat_id: Option<&str>) -> StorageResult<Vec<(UserKey, TopUserValue)>> {
+        self.tables().scan(USERS_TREE, chat_id)
+    }
+
+    fn update_user(&self, key: &UserKey, f: Update<'_, TopUserValue>) -> StorageResult<Option<TopUserValue>> {
+        self.tables().update(USERS_TREE, key.to_bytes()?, f)
+    }
+
+    fn find_settings(&self, chat_id: &str) -> StorageResult<Option<ChatSettings>> {
+        self.tables().get(SETTINGS_TREE, chat_id.as_bytes())
+    }
+
+    fn save_settings(&self, chat_id: &str, settings: &ChatSettings) -> StorageResult<()> {
+        self.tables().put(SETTINGS_TREE, chat_id.as_bytes().to_vec(), settings)
+    }
+
+    fn remove_settings(&self, chat_id: &str) -> StorageResult<()> {
+        self.tables().remove(SETTINGS_TREE, chat_id.as_bytes())
+    }
+
+    fn all_settings(&self) -> StorageResult<Vec<(String, ChatSettings)>> {
+        self.tables().all(SETTINGS_TREE)
+    }
+
+    fn find_rules(&self, key: &str) -> StorageResult<Option<Vec<Rule>>> {
+        self.tables().get(RULES_TREE, key.as_bytes())
+    }
+
+    fn save_rules(&self, key: &str, rules: &[Rule]) -> StorageResult<()> {
+        self.tables().put(RULES_TREE, key.as_bytes().to_vec(), rules)
+    }
+
+    fn all_rules(&self) -> StorageResult<Vec<(String, Vec<Rule>)>> {
+        self.tables().all(RULES_TREE)
+    }
+
+    fn count_duplicate(&self, messages: &[MessageKey], user: Option<&UserKey>, username: Option<&str>,
+                       now: DateTime<Utc>) -> StorageResult<()> {
+        let mut tables = self.tables();
+        // decoded before anything changes, so that a failure changes nothing
+        let mut counted = vec![];
+        for key in messages {
+            if let Some(mut info) = tables.get::<MessageInfo>(MESSAGES_TREE, &key.to_bytes()?)? {
+                info.count += 1;
+                counted.push((key.to_bytes()?, serde_json::to_vec(&info)?));
+            }
+        }
+        let user = match user {
+            Some(key) => {
+                let previous = tables.get::<TopUserValue>(USERS_TREE, &key.to_bytes()?)?;
+                Some((key.to_bytes()?, serde_json::to_vec(&counted_user(previous, username, now))?))
+            },
+            None => None,
+        };
+        tables.table(MESSAGES_TREE).extend(counted);
+        tables.table(USERS_TREE).extend(user);
+        Ok(())
+    }
+
+    fn quarantined(&self) -> StorageResult<Vec<Quarantined>> {
+        Ok(self.tables().quarantine.iter()
+               .map(|((table, key), value)| Quarantined { table: table.clone(), key: key.clone(), value: value.clone() })
+               .collect())
+    }
+}
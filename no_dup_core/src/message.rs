@@ -0,0 +1,165 @@
+use tracing::debug;
+use url::Url;
+
+/// The user who sent a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sender {
+    pub id: i64,
+    // display name, used on the top board
+    pub name: String,
+}
+
+/// Where a forwarded message originally came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardOrigin {
+    // forwarded from a channel (or a group), username is only known for public ones
+    Chat {
+        username: Option<String>,
+        message_id: Option<i32>,
+    },
+    // forwarded from a user
+    User,
+}
+
+/// Kind of the media attached to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MediaKind {
+    Photo,
+    Video,
+    Animation,
+    VideoNote,
+    Sticker,
+    Voice,
+    Audio,
+    Document,
+}
+
+impl MediaKind {
+    /// Whether a frame of it gets hashed, so that re-encoded copies still
+    /// match. Other kinds only match the very same file.
+    pub fn has_keyframe(self) -> bool {
+        matches!(self, MediaKind::Video | MediaKind::Animation | MediaKind::VideoNote)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MediaKind::Photo => "photo",
+            MediaKind::Video => "video",
+            MediaKind::Animation => "animation",
+            MediaKind::VideoNote => "video_note",
+            MediaKind::Sticker => "sticker",
+            MediaKind::Voice => "voice",
+            MediaKind::Audio => "audio",
+            MediaKind::Document => "document",
+        }
+    }
+
+    /// Name used in replies.
+    pub fn name(self) -> &'static str {
+        match self {
+            MediaKind::Photo => "图片",
+            MediaKind::Video => "视频",
+            MediaKind::Animation => "动图",
+            MediaKind::VideoNote => "视频消息",
+            MediaKind::Sticker => "贴纸",
+            MediaKind::Voice => "语音",
+            MediaKind::Audio => "音频",
+            MediaKind::Document => "文件",
+        }
+    }
+}
+
+/// A message as seen by the detector, independent of any bot framework.
+#[derive(Debug, Clone, Default)]
+pub struct IncomingMessage {
+    // raw chat id as reported by Telegram, e.g. -1001234567890
+    pub chat_id: i64,
+    pub chat_username: Option<String>,
+    pub is_private: bool,
+    pub message_id: i32,
+    pub sender: Option<Sender>,
+    // text of the message, or caption of a media message
+    pub text: Option<String>,
+    // links anywhere in the text, as written or behind a text link
+    pub links: Vec<String>,
+    pub forward: Option<ForwardOrigin>,
+    // kind of the media attached to the message, a photo if only its bytes are known
+    pub media: Option<MediaKind>,
+    // Telegram's file_unique_id of the media, the same for every re-send of it
+    pub file_unique_id: Option<String>,
+    // Telegram's file_id of what was downloaded for hashing, to download it again
+    pub photo_file_id: Option<String>,
+    // raw bytes of the photo, or of a frame of a video, not needed when
+    // file_unique_id was seen before
+    pub photo: Option<Vec<u8>>,
+}
+
+/// Chat id as used in keys and `t.me/c/` links, i.e. without the `-100` prefix
+/// of supergroups and channels.
+pub fn clean_chat_id(chat_id: i64) -> String {
+    let id_str = chat_id.to_string();
+    id_str.strip_prefix("-100")
+          .map_or(id_str.clone(), String::from)
+}
+
+impl IncomingMessage {
+    pub fn clean_chat_id(&self) -> String {
+        clean_chat_id(self.chat_id)
+    }
+
+    pub fn is_forward(&self) -> bool {
+        self.forward.is_some()
+    }
+
+    pub fn media_kind(&self) -> Option<MediaKind> {
+        match (self.media, &self.photo) {
+            (Some(kind), _) => Some(kind),
+            (None, Some(_)) => Some(MediaKind::Photo),
+            (None, None) => None,
+        }
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.media_kind() == Some(MediaKind::Photo)
+    }
+
+    pub fn user_id(&self) -> Option<i64> {
+        self.sender.as_ref().map(|u| u.id)
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.sender.as_ref().map(|u| u.name.clone())
+    }
+
+    /// Link to this message in its chat.
+    pub fn link(&self) -> Option<Url> {
+        if self.is_private {
+            return None;
+        }
+        let url = match &self.chat_username {
+            // If it's public group (i.e. not DM, not private group), we can produce
+            // "normal" t.me link (accesible to everyone).
+            Some(username) => format!("https://t.me/{0}/{1}/", username, self.message_id),
+            // For private groups we produce "private" t.me/c links. These are only
+            // accesible to the group members.
+            None => format!("https://t.me/c/{0}/{1}/", self.clean_chat_id(), self.message_id),
+        };
+        Url::parse(&url).ok()
+    }
+
+    /// Link to the original message of a forward from a public channel.
+    pub fn forward_link(&self) -> Option<Url> {
+        match &self.forward {
+            Some(ForwardOrigin::Chat { username: Some(username), message_id: Some(message_id) }) => {
+                let url = Url::parse(&format!("https://t.me/{}/{}", username, message_id)).ok();
+                debug!("&url is {:?}", &url);
+                url
+            },
+            origin => {
+                debug!("Parse forwarded message failed");
+                debug!("origin is {:?}", origin);
+                None
+            }
+        }
+    }
+}
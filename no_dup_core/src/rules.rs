@@ -0,0 +1,159 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::canonical::strip_mirror_prefix;
+use crate::storage::Storage;
+
+/// Key under which the rules shared by every chat are stored.
+pub static GLOBAL_RULES: &str = "global";
+
+/// Stands for the id of the chat a link is sent in, e.g. `t.me/c/{chat}/*`
+/// matches links to messages of that chat.
+pub static CHAT_PLACEHOLDER: &str = "{chat}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    // never report matching urls
+    Ignore,
+    // always report matching urls, even if a built-in filter would skip them
+    Track,
+    // drop query and fragment of matching urls before comparing them
+    Normalize,
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RuleAction::Ignore => "ignore",
+            RuleAction::Track => "track",
+            RuleAction::Normalize => "normalize",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A domain (matching itself and its subdomains), or a glob pattern with `*`
+/// and `?` matched against host and path, e.g. `t.me/joinchat/*`. The host
+/// is matched regardless of case, the path is not. Links are
+/// matched in their canonical form, so the host of a pattern loses its `www.`
+/// or `m.` prefix too.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub pattern: String,
+    pub action: RuleAction,
+}
+
+impl Rule {
+    pub fn new(pattern: &str, action: RuleAction) -> Self {
+        let pattern = pattern.trim();
+        let pattern = ["https://", "http://"].iter()
+            .find(|scheme| pattern.get(..scheme.len()).is_some_and(|p| p.eq_ignore_ascii_case(scheme)))
+            .map_or(pattern, |scheme| &pattern[scheme.len()..]);
+        // hosts are case-insensitive, paths are not
+        let (host, path) = pattern.split_at(pattern.find('/').unwrap_or(pattern.len()));
+        Rule { pattern: format!("{}{}", strip_mirror_prefix(&host.to_lowercase()), path), action }
+    }
+
+    fn is_glob(&self) -> bool {
+        self.pattern.contains(['/', '*', '?'])
+    }
+
+    /// Whether `url`, sent in `chat_id`, matches.
+    pub fn matches(&self, chat_id: &str, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        if self.is_glob() {
+            let mut target = format!("{}{}", host, url.path());
+            if let Some(query) = url.query() {
+                target.push('?');
+                target.push_str(query);
+            }
+            let pattern = self.pattern.replace(CHAT_PLACEHOLDER, chat_id);
+            glob_match(pattern.as_bytes(), target.as_bytes())
+        } else {
+            host == self.pattern || host.ends_with(&format!(".{}", self.pattern))
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.action, self.pattern)
+    }
+}
+
+// `*` matches any sequence, `?` any single byte
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*`
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Rules shared by every chat until they are changed.
+pub fn default_global_rules() -> Vec<Rule> {
+    vec![Rule::new("github.com", RuleAction::Ignore),
+         Rule::new("stackoverflow.com", RuleAction::Ignore),
+         // links to messages of the chat itself, and invitations
+         Rule::new("t.me/c/{chat}/*", RuleAction::Ignore),
+         Rule::new("t.me/joinchat/*", RuleAction::Ignore)]
+}
+
+/// The rules that apply to a chat.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    pub chat: Vec<Rule>,
+    pub global: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Action of the first matching rule for `url` sent in `chat_id`, the
+    /// chat's own rules go first.
+    pub fn decide(&self, chat_id: &str, url: &Url) -> Option<RuleAction> {
+        self.chat.iter()
+                 .chain(self.global.iter())
+                 .find(|r| r.matches(chat_id, url))
+                 .map(|r| r.action)
+    }
+}
+
+pub fn find_rules(storage: &dyn Storage, key: &str) -> Option<Vec<Rule>> {
+    match storage.find_rules(key) {
+        Ok(rules) => rules,
+        Err(e) => {
+            warn!("rules database get error {:?} when looking for {:?}", &e, key);
+            None
+        }
+    }
+}
+
+pub fn save_rules(storage: &dyn Storage, key: &str, rules: &[Rule]) -> bool {
+    if let Err(e) = storage.save_rules(key, rules) {
+        warn!("rules database error {:?} when saving {:?} with value {:?}", &e, key, rules);
+        false
+    } else {
+        info!("Rules of {:?} saved: {:?}", key, rules);
+        true
+    }
+}
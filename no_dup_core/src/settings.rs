@@ -0,0 +1,249 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::detector::{DuplicateKind, DEFAULT_WINDOW_HOURS};
+use crate::image::TIME_OUT_DAYS;
+use crate::storage::Storage;
+use crate::template::{self, FORWARD_TEMPLATE, IMAGE_TEMPLATE, LINK_TEMPLATE, MEDIA_TEMPLATE};
+use crate::url_filter::SHORT_PATH_LEN;
+
+/// Longest period the `*_days` and `*_hours` settings can cover, ten years.
+pub const MAX_DAYS: i64 = 10 * 365;
+
+/// Tunables of a single chat. Chats without a stored record use the defaults
+/// of the deployment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatSettings {
+    // images with a hash distance below this are considered the same
+    pub threshold: u32,
+    // images not matched for this many days are no longer compared against
+    pub timeout_days: i64,
+    // the largest photo width to download for hashing
+    pub max_photo_width: u32,
+    // how long to wait before checking whether the duplicate was deleted
+    pub delete_check_secs: u64,
+    // only occurrences within this many hours count as duplicates
+    pub window_hours: i64,
+    // records not seen for this many days are garbage collected
+    pub retention_days: i64,
+    // links whose path and query are at most this long are never duplicates
+    pub short_path_len: usize,
+    // also match rotated, mirrored and cropped images, at the cost of storing
+    // and looking up a hash of each variant
+    pub robust_matching: bool,
+    // replies to each kind of duplicate, see template::PLACEHOLDERS
+    pub forward_template: String,
+    pub link_template: String,
+    pub image_template: String,
+    pub media_template: String,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            threshold: 4,
+            timeout_days: TIME_OUT_DAYS,
+            max_photo_width: 600,
+            delete_check_secs: 30,
+            window_hours: DEFAULT_WINDOW_HOURS,
+            retention_days: TIME_OUT_DAYS,
+            short_path_len: SHORT_PATH_LEN,
+            robust_matching: false,
+            forward_template: String::from(FORWARD_TEMPLATE),
+            link_template: String::from(LINK_TEMPLATE),
+            image_template: String::from(IMAGE_TEMPLATE),
+            media_template: String::from(MEDIA_TEMPLATE),
+        }
+    }
+}
+
+impl ChatSettings {
+    pub const KEYS: &'static [&'static str] = &[
+        "threshold",
+        "timeout_days",
+        "max_photo_width",
+        "delete_check_secs",
+        "window_hours",
+        "retention_days",
+        "short_path_len",
+        "robust_matching",
+        "forward_template",
+        "link_template",
+        "image_template",
+        "media_template",
+    ];
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let value = match key {
+            "threshold" => self.threshold.to_string(),
+            "timeout_days" => self.timeout_days.to_string(),
+            "max_photo_width" => self.max_photo_width.to_string(),
+            "delete_check_secs" => self.delete_check_secs.to_string(),
+            "window_hours" => self.window_hours.to_string(),
+            "retention_days" => self.retention_days.to_string(),
+            "short_path_len" => self.short_path_len.to_string(),
+            "robust_matching" => self.robust_matching.to_string(),
+            "forward_template" => self.forward_template.clone(),
+            "link_template" => self.link_template.clone(),
+            "image_template" => self.image_template.clone(),
+            "media_template" => self.media_template.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Sets `key` from its textual form, as shown by [`ChatSettings::get`].
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let invalid = |e: &dyn fmt::Display| anyhow!("invalid value {:?} for {}: {}", value, key, e);
+        // periods have to be positive and fit in a date
+        let period = |max: i64| -> Result<i64> {
+            match value.parse::<i64>() {
+                Ok(n) if (1..=max).contains(&n) => Ok(n),
+                Ok(_) => Err(invalid(&format_args!("expected a number from 1 to {}", max))),
+                Err(e) => Err(invalid(&e)),
+            }
+        };
+        // a typo in a placeholder would end up in every reply
+        let template = || -> Result<String> {
+            match template::unknown_placeholders(value).as_slice() {
+                [] => Ok(String::from(value)),
+                unknown => Err(invalid(&format_args!("unknown {}, expected some of {}",
+                                                     unknown.join(", "), template::PLACEHOLDERS.join(", ")))),
+            }
+        };
+        match key {
+            "threshold" => self.threshold = value.parse().map_err(|e| invalid(&e))?,
+            "timeout_days" => self.timeout_days = period(MAX_DAYS)?,
+            "max_photo_width" => self.max_photo_width = value.parse().map_err(|e| invalid(&e))?,
+            "delete_check_secs" => self.delete_check_secs = value.parse().map_err(|e| invalid(&e))?,
+            "window_hours" => self.window_hours = period(MAX_DAYS * 24)?,
+            "retention_days" => self.retention_days = period(MAX_DAYS)?,
+            "short_path_len" => self.short_path_len = value.parse().map_err(|e| invalid(&e))?,
+            "robust_matching" => self.robust_matching = value.parse().map_err(|e| invalid(&e))?,
+            "forward_template" => self.forward_template = template()?,
+            "link_template" => self.link_template = template()?,
+            "image_template" => self.image_template = template()?,
+            "media_template" => self.media_template = template()?,
+            _ => return Err(anyhow!("unknown setting {:?}, expected one of {}", key, Self::KEYS.join(", "))),
+        }
+        Ok(())
+    }
+
+    /// Reply template for duplicates of `kind`.
+    pub fn template(&self, kind: DuplicateKind) -> &str {
+        match kind {
+            DuplicateKind::Forward => &self.forward_template,
+            DuplicateKind::Link => &self.link_template,
+            DuplicateKind::Image => &self.image_template,
+            DuplicateKind::Media(_) => &self.media_template,
+        }
+    }
+}
+
+impl fmt::Display for ChatSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for key in Self::KEYS {
+            writeln!(f, "{} = {}", key, self.get(key).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+/// The time `days` days before `now`. Periods that are not positive or reach
+/// beyond what dates can hold, e.g. from settings stored before they were
+/// checked, go back to the earliest date there is.
+pub fn days_before(now: DateTime<Utc>, days: i64) -> DateTime<Utc> {
+    seconds_before(now, days.checked_mul(24 * 60 * 60))
+}
+
+/// Like [`days_before`], in hours.
+pub fn hours_before(now: DateTime<Utc>, hours: i64) -> DateTime<Utc> {
+    seconds_before(now, hours.checked_mul(60 * 60))
+}
+
+fn seconds_before(now: DateTime<Utc>, seconds: Option<i64>) -> DateTime<Utc> {
+    // Duration::seconds panics beyond i64::MAX milliseconds
+    seconds.filter(|s| *s > 0 && *s <= i64::MAX / 1000)
+           .and_then(|s| now.checked_sub_signed(Duration::seconds(s)))
+           .unwrap_or(chrono::MIN_DATETIME)
+}
+
+/// Settings stored for `chat_id`, if any.
+pub fn find_settings(storage: &dyn Storage, chat_id: &str) -> Option<ChatSettings> {
+    match storage.find_settings(chat_id) {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!("settings database get error {:?} when looking for chat {:?}", &e, chat_id);
+            None
+        }
+    }
+}
+
+/// Every stored settings record, with the chat it belongs to.
+pub fn all_settings(storage: &dyn Storage) -> Vec<(String, ChatSettings)> {
+    match storage.all_settings() {
+        Ok(all) => all,
+        Err(e) => {
+            warn!("settings database error {:?} when listing chats", &e);
+            vec![]
+        }
+    }
+}
+
+pub fn save_settings(storage: &dyn Storage, chat_id: &str, settings: &ChatSettings) -> bool {
+    if let Err(e) = storage.save_settings(chat_id, settings) {
+        warn!("settings database error {:?} when saving chat {:?} with value {:?}", &e, chat_id, settings);
+        false
+    } else {
+        info!("Settings of chat {:?} saved: {:?}", chat_id, settings);
+        true
+    }
+}
+
+pub fn reset_settings(storage: &dyn Storage, chat_id: &str) -> bool {
+    storage.remove_settings(chat_id).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods_out_of_range_are_refused() {
+        let mut settings = ChatSettings::default();
+        for (key, max) in [("window_hours", MAX_DAYS * 24), ("retention_days", MAX_DAYS), ("timeout_days", MAX_DAYS)] {
+            for value in ["0", "-1", "100000000000", &(max + 1).to_string()] {
+                assert!(settings.set(key, value).is_err(), "{} = {}", key, value);
+            }
+            settings.set(key, &max.to_string()).unwrap();
+            assert_eq!(settings.get(key), Some(max.to_string()));
+        }
+        assert_eq!(settings.threshold, ChatSettings::default().threshold);
+        assert!(settings.set("robust_matching", "yes").is_err());
+    }
+
+    #[test]
+    fn huge_periods_reach_back_to_the_earliest_date() {
+        let now = Utc::now();
+        assert_eq!(days_before(now, 1), now - Duration::days(1));
+        assert_eq!(hours_before(now, 2), now - Duration::hours(2));
+        for n in [i64::MAX, i64::MAX / 24, 100_000_000_000, 0, -5] {
+            assert_eq!(days_before(now, n), chrono::MIN_DATETIME);
+            assert_eq!(hours_before(now, n), chrono::MIN_DATETIME);
+        }
+    }
+
+    #[test]
+    fn templates_with_unknown_placeholders_are_refused() {
+        let mut settings = ChatSettings::default();
+        settings.set("link_template", "{first_user}：{url} 第{count}次").unwrap();
+        assert_eq!(settings.link_template, "{first_user}：{url} 第{count}次");
+        assert!(settings.set("image_template", "第{cuont}次").is_err());
+        assert_eq!(settings.image_template, ChatSettings::default().image_template);
+    }
+}
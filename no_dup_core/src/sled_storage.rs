@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use tracing::warn;
+
+use crate::image::FILE_ID_TREE;
+use crate::keys::{self, BinaryKey};
+use crate::layout;
+use crate::rules::Rule;
+use crate::settings::ChatSettings;
+use crate::storage::{counted_user, Quarantined, Storage, StorageError, StorageResult, Update};
+use crate::store::{FileIdKey, ImageKey, ImageValue, MessageInfo, MessageKey, TopUserValue, UserKey};
+
+/// Trees of the database, one for each kind of record. File ids of media are
+/// in [`FILE_ID_TREE`].
+pub static MESSAGES_TREE: &str = "messages";
+pub static IMAGES_TREE: &str = "images";
+pub static USERS_TREE: &str = "users";
+pub static SETTINGS_TREE: &str = "settings";
+pub static RULES_TREE: &str = "rules";
+// records that could not be decoded, keyed by their tree and key
+pub static QUARANTINE_TREE: &str = "quarantine";
+
+/// [`Storage`] in the trees of a sled database, with keys in the layout of
+/// [`BinaryKey`] and values as JSON.
+pub struct SledStorage {
+    messages: sled::Tree,
+    images: sled::Tree,
+    file_ids: sled::Tree,
+    users: sled::Tree,
+    settings: sled::Tree,
+    rules: sled::Tree,
+    quarantine: sled::Tree,
+}
+
+impl SledStorage {
+    /// Opens the database at `path`, which must not need a
+    /// [`layout::migrate`].
+    pub fn open(path: &str) -> Result<Self> {
+        if layout::has_old_layout(path) {
+            return Err(anyhow!("the databases next to {} use the old layout, run `no_dup_bot migrate` first", path));
+        }
+        let db = sled::open(path)?;
+        if keys::needs_migration(&db) {
+            return Err(anyhow!("{} uses the old key layout, run `no_dup_bot migrate` first", path));
+        }
+        SledStorage::new(&db)
+    }
+
+    pub fn new(db: &sled::Db) -> Result<Self> {
+        Ok(SledStorage {
+            messages: db.open_tree(MESSAGES_TREE)?,
+            images: db.open_tree(IMAGES_TREE)?,
+            file_ids: db.open_tree(FILE_ID_TREE)?,
+            users: db.open_tree(USERS_TREE)?,
+            settings: db.open_tree(SETTINGS_TREE)?,
+            rules: db.open_tree(RULES_TREE)?,
+            quarantine: db.open_tree(QUARANTINE_TREE)?,
+        })
+    }
+}
+
+impl SledStorage {
+    fn get<V: DeserializeOwned>(&self, tree: &sled::Tree, key: &[u8]) -> StorageResult<Option<V>> {
+        match tree.get(key)? {
+            Some(value) => Ok(Some(self.decode(tree, key, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn scan<K: BinaryKey, V: DeserializeOwned>(&self, tree: &sled::Tree, chat_id: Option<&str>)
+                                                -> StorageResult<Vec<(K, V)>> {
+        let iter = match chat_id {
+            Some(chat_id) => tree.scan_prefix(K::prefix(chat_id)?),
+            None => tree.iter(),
+        };
+        let mut records = vec![];
+        for item in iter {
+            let (key, value) = item?;
+            // other records of the tree, if any
+            let record_key = match K::from_bytes(&key) {
+                Some(record_key) => record_key,
+                None => continue,
+            };
+            // the others are still worth listing
+            if let Ok(value) = self.decode(tree, &key, &value) {
+                records.push((record_key, value));
+            }
+        }
+        Ok(records)
+    }
+
+    // every record of a tree keyed by text
+    fn all<V: DeserializeOwned>(&self, tree: &sled::Tree) -> StorageResult<Vec<(String, V)>> {
+        let mut all = vec![];
+        for item in tree.iter() {
+            let (key, value) = item?;
+            if let Ok(value) = self.decode(tree, &key, &value) {
+                all.push((String::from_utf8_lossy(&key).into_owned(), value));
+            }
+        }
+        Ok(all)
+    }
+
+    // a single compare-and-swap, tried again when another write got in first
+    fn update<V: Serialize + DeserializeOwned>(&self, tree: &sled::Tree, key: &[u8],
+                                               f: Update<'_, V>) -> StorageResult<Option<V>> {
+        loop {
+            let current = tree.get(key)?;
+            let value = match &current {
+                Some(value) => Some(self.decode(tree, key, value)?),
+                None => None,
+            };
+            let updated = f(value);
+            let encoded = match &updated {
+                Some(value) => Some(serde_json::to_vec(value)?),
+                None => None,
+            };
+            if tree.compare_and_swap(key, current, encoded)?.is_ok() {
+                return Ok(updated);
+            }
+        }
+    }
+
+    fn decode<V: DeserializeOwned>(&self, tree: &sled::Tree, key: &[u8], value: &[u8]) -> StorageResult<V> {
+        serde_json::from_slice(value).map_err(|e| self.quarantine(tree, key, value, e))
+    }
+
+    // moves an undecodable record out of `tree`, so that it no longer gets in
+    // the way of the others
+    fn quarantine(&self, tree: &sled::Tree, key: &[u8], value: &[u8], e: serde_json::Error) -> StorageError {
+        let table = String::from_utf8_lossy(&tree.name()).into_owned();
+        warn!("Moving corrupt record {} in {} to the quarantine: {}", key.escape_ascii(), &table, e);
+        let mut quarantine_key = table.clone().into_bytes();
+        quarantine_key.push(b'/');
+        quarantine_key.extend_from_slice(key);
+        let moved = (tree, &self.quarantine).transaction(|(tree, quarantine)| {
+            quarantine.insert(quarantine_key.as_slice(), value)?;
+            tree.remove(key)?;
+            Ok::<_, ConflictableTransactionError>(())
+        });
+        if let Err(e) = moved {
+            warn!("Failed to quarantine {} in {}: {:?}", key.escape_ascii(), &table, e);
+        }
+        StorageError::Corrupt { table, key: key.to_vec() }
+    }
+}
+
+fn put<V: Serialize + ?Sized>(tree: &sled::Tree, key: &[u8], value: &V) -> StorageResult<()> {
+    tree.insert(key, serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+// why a transaction counting a duplicate gave up
+enum CountAborted {
+    Corrupt(&'static str, Vec<u8>, sled::IVec, serde_json::Error),
+    Serde(serde_json::Error),
+}
+
+impl Storage for SledStorage {
+    fn find_message(&self, key: &MessageKey) -> StorageResult<Option<MessageInfo>> {
+        self.get(&self.messages, &key.to_bytes()?)
+    }
+
+    fn save_message(&self, key: &MessageKey, info: &MessageInfo) -> StorageResult<()> {
+        put(&self.messages, &key.to_bytes()?, info)
+    }
+
+    fn remove_message(&self, key: &MessageKey) -> StorageResult<()> {
+        self.messages.remove(key.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn messages(&self, chat_id: Option<&str>) -> StorageResult<Vec<(MessageKey, MessageInfo)>> {
+        self.scan(&self.messages, chat_id)
+    }
+
+    fn update_message(&self, key: &MessageKey, f: Update<'_, MessageInfo>) -> StorageResult<Option<MessageInfo>> {
+        self.update(&self.messages, &key.to_bytes()?, f)
+    }
+
+    fn find_image(&self, key: &ImageKey) -> StorageResult<Option<ImageValue>> {
+        self.get(&self.images, &key.to_bytes()?)
+    }
+
+    fn save_image(&self, key: &ImageKey, value: &ImageValue) -> StorageResult<()> {
+        put(&self.images, &key.to_bytes()?, value)
+    }
+
+    fn remove_image(&self, key: &ImageKey) -> StorageResult<()> {
+        self.images.remove(key.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn images(&self, chat_id: Option<&str>) -> StorageResult<Vec<(ImageKey, ImageValue)>> {
+        self.scan(&self.images, chat_id)
+    }
+
+    fn find_file(&self, key: &FileIdKey) -> StorageResult<Option<ImageValue>> {
+        self.get(&self.file_ids, &key.to_bytes()?)
+    }
+
+    fn save_file(&self, key: &FileIdKey, value: &ImageValue) -> StorageResult<()> {
+        put(&self.file_ids, &key.to_bytes()?, value)
+    }
+
+    fn remove_file(&self, key: &FileIdKey) -> StorageResult<()> {
+        self.file_ids.remove(key.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn files(&self, chat_id: Option<&str>) -> StorageResult<Vec<(FileIdKey, ImageValue)>> {
+        self.scan(&self.file_ids, chat_id)
+    }
+
+    fn find_user(&self, key: &UserKey) -> StorageResult<Option<TopUserValue>> {
+        self.get(&self.users, &key.to_bytes()?)
+    }
+
+    fn save_user(&self, key: &UserKey, value: &TopUserValue) -> StorageResult<()> {
+        put(&self.users, &key.to_bytes()?, value)
+    }
+
+    fn remove_user(&self, key: &UserKey) -> StorageResult<()> {
+        self.users.remove(key.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn users(&self, chat_id: Option<&str>) -> StorageResult<Vec<(UserKey, TopUserValue)>> {
+        self.scan(&self.users, chat_id)
+    }
+
+    fn update_user(&self, key: &UserKey, f: Update<'_, TopUserValue>) -> StorageResult<Option<TopUserValue>> {
+        self.update(&self.users, &key.to_bytes()?, f)
+    }
+
+    fn find_settings(&self, chat_id: &str) -> StorageResult<Option<ChatSettings>> {
+        self.get(&self.settings, chat_id.as_bytes())
+    }
+
+    fn save_settings(&self, chat_id: &str, settings: &ChatSettings) -> StorageResult<()> {
+        put(&self.settings, chat_id.as_bytes(), settings)
+    }
+
+    fn remove_settings(&self, chat_id: &str) -> StorageResult<()> {
+        self.settings.remove(chat_id.as_bytes())?;
+        Ok(())
+    }
+
+    fn all_settings(&self) -> StorageResult<Vec<(String, ChatSettings)>> {
+        self.all(&self.settings)
+    }
+
+    fn find_rules(&self, key: &str) -> StorageResult<Option<Vec<Rule>>> {
+        self.get(&self.rules, key.as_bytes())
+    }
+
+    fn save_rules(&self, key: &str, rules: &[Rule]) -> StorageResult<()> {
+        put(&self.rules, key.as_bytes(), rules)
+    }
+
+    fn all_rules(&self) -> StorageResult<Vec<(String, Vec<Rule>)>> {
+        self.all(&self.rules)
+    }
+
+    fn count_duplicate(&self, messages: &[MessageKey], user: Option<&UserKey>, username: Option<&str>,
+                       now: DateTime<Utc>) -> StorageResult<()> {
+        let abort = ConflictableTransactionError::Abort;
+        let messages = messages.iter().map(BinaryKey::to_bytes).collect::<Result<Vec<_>, _>>()?;
+        let user = user.map(BinaryKey::to_bytes).transpose()?;
+        let counted = (&self.messages, &self.users).transaction(|(message_tree, user_tree)| {
+            for key in &messages {
+                let key = key.clone();
+                if let Some(value) = message_tree.get(&key)? {
+                    let mut info = serde_json::from_slice::<MessageInfo>(&value)
+                        .map_err(|e| abort(CountAborted::Corrupt(MESSAGES_TREE, key.clone(), value, e)))?;
+                    info.count += 1;
+                    let value = serde_json::to_vec(&info).map_err(|e| abort(CountAborted::Serde(e)))?;
+                    message_tree.insert(key, value)?;
+                }
+            }
+            if let Some(key) = &user {
+                let key = key.clone();
+                let previous = match user_tree.get(&key)? {
+                    Some(value) => Some(serde_json::from_slice::<TopUserValue>(&value)
+                        .map_err(|e| abort(CountAborted::Corrupt(USERS_TREE, key.clone(), value, e)))?),
+                    None => None,
+                };
+                let value = serde_json::to_vec(&counted_user(previous, username, now))
+                    .map_err(|e| abort(CountAborted::Serde(e)))?;
+                user_tree.insert(key, value)?;
+            }
+            Ok(())
+        });
+        match counted {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+            Err(TransactionError::Abort(CountAborted::Serde(e))) => Err(e.into()),
+            // nothing was written, the record can be moved out of the way
+            Err(TransactionError::Abort(CountAborted::Corrupt(tree, key, value, e))) => {
+                let tree = if tree == MESSAGES_TREE { &self.messages } else { &self.users };
+                Err(self.quarantine(tree, &key, &value, e))
+            },
+        }
+    }
+
+    fn quarantined(&self) -> StorageResult<Vec<Quarantined>> {
+        let mut records = vec![];
+        for item in self.quarantine.iter() {
+            let (key, value) = item?;
+            let (table, key) = match key.iter().position(|c| *c == b'/') {
+                Some(i) => (String::from_utf8_lossy(&key[..i]).into_owned(), key[i + 1..].to_vec()),
+                None => (String::new(), key.to_vec()),
+            };
+            records.push(Quarantined { table, key, value: value.to_vec() });
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Occurrence;
+    use url::Url;
+
+    #[test]
+    fn a_corrupt_record_aborts_the_count_and_is_quarantined() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = SledStorage::new(&db).unwrap();
+        let [good, corrupt] = ["https://example.com/a", "https://example.com/b"].map(|url| {
+            MessageKey { chat_id: String::from("1"), url: Url::parse(url).unwrap() }
+        });
+        storage.save_message(&good, &MessageInfo::new(good.url.clone(), Occurrence {
+            seen_at: Utc::now(), link: None, user_id: None, username: None, message_id: Some(1),
+        })).unwrap();
+        db.open_tree(MESSAGES_TREE).unwrap().insert(corrupt.to_bytes().unwrap(), &b"{not json"[..]).unwrap();
+        let user = UserKey { chat_id: String::from("1"), user_id: 42 };
+
+        let counted = storage.count_duplicate(&[good.clone(), corrupt.clone()], Some(&user), None, Utc::now());
+        assert!(matches!(counted, Err(StorageError::Corrupt { .. })));
+        // all or nothing
+        assert_eq!(storage.find_message(&good).unwrap().unwrap().count, 1);
+        assert!(storage.find_user(&user).unwrap().is_none());
+        assert!(storage.find_message(&corrupt).unwrap().is_none());
+        assert_eq!(storage.quarantined().unwrap().len(), 1);
+
+        storage.count_duplicate(&[good.clone(), corrupt], Some(&user), None, Utc::now()).unwrap();
+        assert_eq!(storage.find_message(&good).unwrap().unwrap().count, 2);
+    }
+}
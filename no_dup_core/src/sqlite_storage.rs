@@ -0,0 +1,332 @@
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::keys::BinaryKey;
+use crate::rules::Rule;
+use crate::settings::ChatSettings;
+use crate::storage::{counted_user, Quarantined, Storage, StorageError, StorageResult, Update};
+use crate::store::{FileIdKey, ImageKey, ImageValue, MessageInfo, MessageKey, TopUserValue, UserKey};
+
+// tables of records keyed by a `BinaryKey`, the chat id is repeated for listing
+static KEYED_TABLES: &[&str] = &["messages", "images", "file_ids", "users"];
+
+/// [`Storage`] in an SQLite database file. Records with a [`BinaryKey`] go to
+/// a table of their kind with the key as a blob and the chat id on its own
+/// column, values are JSON text.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        for table in KEYED_TABLES {
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (key BLOB PRIMARY KEY, chat_id TEXT NOT NULL, value TEXT NOT NULL);
+                 CREATE INDEX IF NOT EXISTS {table}_chat_id ON {table} (chat_id);",
+                table = table))?;
+        }
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS settings (chat_id TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS rules (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS quarantine (tbl TEXT NOT NULL, key BLOB NOT NULL, value BLOB NOT NULL,
+                                                    PRIMARY KEY (tbl, key));")?;
+        Ok(SqliteStorage { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // a panic elsewhere leaves the connection usable
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// a stored key or value as bytes, whatever SQLite typed it as
+fn bytes_of(value: Value) -> Vec<u8> {
+    match value {
+        Value::Null => vec![],
+        Value::Integer(i) => i.to_string().into_bytes(),
+        Value::Real(f) => f.to_string().into_bytes(),
+        Value::Text(s) => s.into_bytes(),
+        Value::Blob(b) => b,
+    }
+}
+
+fn get_raw(conn: &Connection, table: &str, column: &str, key: &Value) -> StorageResult<Option<Vec<u8>>> {
+    let value = conn.prepare_cached(&format!("SELECT value FROM {} WHERE {} = ?1", table, column))?
+                    .query_row(params![key], |row| row.get::<_, Value>(0))
+                    .optional()?;
+    Ok(value.map(bytes_of))
+}
+
+fn get<V: DeserializeOwned>(conn: &Connection, table: &str, column: &str, key: Value) -> StorageResult<Option<V>> {
+    match get_raw(conn, table, column, &key)? {
+        Some(value) => Ok(Some(decode(conn, table, column, &key, &value)?)),
+        None => Ok(None),
+    }
+}
+
+fn decode<V: DeserializeOwned>(conn: &Connection, table: &str, column: &str, key: &Value,
+                               value: &[u8]) -> StorageResult<V> {
+    serde_json::from_slice(value).map_err(|e| quarantine(conn, table, column, key, value, e))
+}
+
+// moves an undecodable record out of `table`, so that it no longer gets in the
+// way of the others
+fn quarantine(conn: &Connection, table: &str, column: &str, key: &Value, value: &[u8],
+              e: serde_json::Error) -> StorageError {
+    let key_bytes = bytes_of(key.clone());
+    warn!("Moving corrupt record {} in {} to the quarantine: {}", key_bytes.escape_ascii(), table, e);
+    let moved = conn.execute("INSERT OR REPLACE INTO quarantine (tbl, key, value) VALUES (?1, ?2, ?3)",
+                             params![table, &key_bytes, value])
+                    .and_then(|_| conn.execute(&format!("DELETE FROM {} WHERE {} = ?1", table, column), params![key]));
+    if let Err(e) = moved {
+        warn!("Failed to quarantine {} in {}: {:?}", key_bytes.escape_ascii(), table, e);
+    }
+    StorageError::Corrupt { table: String::from(table), key: key_bytes }
+}
+
+fn put<K: BinaryKey, V: Serialize>(conn: &Connection, table: &str, key: &K, value: &V) -> StorageResult<()> {
+    conn.prepare_cached(&format!("INSERT OR REPLACE INTO {} (key, chat_id, value) VALUES (?1, ?2, ?3)", table))?
+        .execute(params![key.to_bytes()?, key.chat_id(), serde_json::to_string(value)?])?;
+    Ok(())
+}
+
+fn remove<K: BinaryKey>(conn: &Connection, table: &str, key: &K) -> StorageResult<()> {
+    conn.prepare_cached(&format!("DELETE FROM {} WHERE key = ?1", table))?
+        .execute(params![key.to_bytes()?])?;
+    Ok(())
+}
+
+fn scan<K: BinaryKey, V: DeserializeOwned>(conn: &Connection, table: &str,
+                                           chat_id: Option<&str>) -> StorageResult<Vec<(K, V)>> {
+    let mut statement = match chat_id {
+        Some(_) => conn.prepare_cached(&format!("SELECT key, value FROM {} WHERE chat_id = ?1", table))?,
+        None => conn.prepare_cached(&format!("SELECT key, value FROM {}", table))?,
+    };
+    let row = |row: &rusqlite::Row| Ok((row.get::<_, Value>(0)?, row.get::<_, Value>(1)?));
+    let rows = match chat_id {
+        Some(chat_id) => statement.query_map(params![chat_id], row)?.collect::<rusqlite::Result<Vec<_>>>()?,
+        None => statement.query_map([], row)?.collect::<rusqlite::Result<Vec<_>>>()?,
+    };
+    let mut records = vec![];
+    for (key, value) in rows {
+        let record_key = match K::from_bytes(&bytes_of(key.clone())) {
+            Some(record_key) => record_key,
+            None => continue,
+        };
+        // the others are still worth listing
+        if let Ok(value) = decode(conn, table, "key", &key, &bytes_of(value)) {
+            records.push((record_key, value));
+        }
+    }
+    Ok(records)
+}
+
+// within a transaction that takes the write lock right away, so that other
+// connections to the file cannot get in between either
+fn update<K: BinaryKey, V: Serialize + DeserializeOwned>(conn: &mut Connection, table: &str, key: &K,
+                                                         f: Update<'_, V>) -> StorageResult<Option<V>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let value = match get_raw(&tx, table, "key", &blob(key)?)? {
+        Some(value) => match serde_json::from_slice(&value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                // moved out of the way once the transaction is over
+                drop(tx);
+                return Err(quarantine(conn, table, "key", &blob(key)?, &value, e));
+            }
+        },
+        None => None,
+    };
+    let updated = f(value);
+    match &updated {
+        Some(value) => put(&tx, table, key, value)?,
+        None => remove(&tx, table, key)?,
+    }
+    tx.commit()?;
+    Ok(updated)
+}
+
+// every record of a table keyed by text
+fn all<V: DeserializeOwned>(conn: &Connection, table: &str, column: &str) -> StorageResult<Vec<(String, V)>> {
+    let mut statement = conn.prepare(&format!("SELECT {}, value FROM {}", column, table))?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Value>(1)?)))?
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut all = vec![];
+    for (key, value) in rows {
+        if let Ok(value) = decode(conn, table, column, &text(&key), &bytes_of(value)) {
+            all.push((key, value));
+        }
+    }
+    Ok(all)
+}
+
+fn blob<K: BinaryKey>(key: &K) -> StorageResult<Value> {
+    Ok(Value::Blob(key.to_bytes()?))
+}
+
+fn text(key: &str) -> Value {
+    Value::Text(String::from(key))
+}
+
+impl Storage for SqliteStorage {
+    fn find_message(&self, key: &MessageKey) -> StorageResult<Option<MessageInfo>> {
+        get(&self.conn(), "messages", "key", blob(key)?)
+    }
+
+    fn save_message(&self, key: &MessageKey, info: &MessageInfo) -> StorageResult<()> {
+        put(&self.conn(), "messages", key, info)
+    }
+
+    fn remove_message(&self, key: &MessageKey) -> StorageResult<()> {
+        remove(&self.conn(), "messages", key)
+    }
+
+    fn messages(&self, chat_id: Option<&str>) -> StorageResult<Vec<(MessageKey, MessageInfo)>> {
+        scan(&self.conn(), "messages", chat_id)
+    }
+
+    fn update_message(&self, key: &MessageKey, f: Update<'_, MessageInfo>) -> StorageResult<Option<MessageInfo>> {
+        update(&mut self.conn(), "messages", key, f)
+    }
+
+    fn find_image(&self, key: &ImageKey) -> StorageResult<Option<ImageValue>> {
+        get(&self.conn(), "images", "key", blob(key)?)
+    }
+
+    fn save_image(&self, key: &ImageKey, value: &ImageValue) -> StorageResult<()> {
+        put(&self.conn(), "images", key, value)
+    }
+
+    fn remove_image(&self, key: &ImageKey) -> StorageResult<()> {
+        remove(&self.conn(), "images", key)
+    }
+
+    fn images(&self, chat_id: Option<&str>) -> StorageResult<Vec<(ImageKey, ImageValue)>> {
+        scan(&self.conn(), "images", chat_id)
+    }
+
+    fn find_file(&self, key: &FileIdKey) -> StorageResult<Option<ImageValue>> {
+        get(&self.conn(), "file_ids", "key", blob(key)?)
+    }
+
+    fn save_file(&self, key: &FileIdKey, value: &ImageValue) -> StorageResult<()> {
+        put(&self.conn(), "file_ids", key, value)
+    }
+
+    fn remove_file(&self, key: &FileIdKey) -> StorageResult<()> {
+        remove(&self.conn(), "file_ids", key)
+    }
+
+    fn files(&self, chat_id: Option<&str>) -> StorageResult<Vec<(FileIdKey, ImageValue)>> {
+        scan(&self.conn(), "file_ids", chat_id)
+    }
+
+    fn find_user(&self, key: &UserKey) -> StorageResult<Option<TopUserValue>> {
+        get(&self.conn(), "users", "key", blob(key)?)
+    }
+
+    fn save_user(&self, key: &UserKey, value: &TopUserValue) -> StorageResult<()> {
+        put(&self.conn(), "users", key, value)
+    }
+
+    fn remove_user(&self, key: &UserKey) -> StorageResult<()> {
+        remove(&self.conn(), "users", key)
+    }
+
+    fn users(&self, chat_id: Option<&str>) -> StorageResult<Vec<(UserKey, TopUserValue)>> {
+        scan(&self.conn(), "users", chat_id)
+    }
+
+    fn update_user(&self, key: &UserKey, f: Update<'_, TopUserValue>) -> StorageResult<Option<TopUserValue>> {
+        update(&mut self.conn(), "users", key, f)
+    }
+
+    fn find_settings(&self, chat_id: &str) -> StorageResult<Option<ChatSettings>> {
+        get(&self.conn(), "settings", "chat_id", text(chat_id))
+    }
+
+    fn save_settings(&self, chat_id: &str, settings: &ChatSettings) -> StorageResult<()> {
+        self.conn().execute("INSERT OR REPLACE INTO settings (chat_id, value) VALUES (?1, ?2)",
+                            params![chat_id, serde_json::to_string(settings)?])?;
+        Ok(())
+    }
+
+    fn remove_settings(&self, chat_id: &str) -> StorageResult<()> {
+        self.conn().execute("DELETE FROM settings WHERE chat_id = ?1", params![chat_id])?;
+        Ok(())
+    }
+
+    fn all_settings(&self) -> StorageResult<Vec<(String, ChatSettings)>> {
+        all(&self.conn(), "settings", "chat_id")
+    }
+
+    fn find_rules(&self, key: &str) -> StorageResult<Option<Vec<Rule>>> {
+        get(&self.conn(), "rules", "key", text(key))
+    }
+
+    fn save_rules(&self, key: &str, rules: &[Rule]) -> StorageResult<()> {
+        self.conn().execute("INSERT OR REPLACE INTO rules (key, value) VALUES (?1, ?2)",
+                            params![key, serde_json::to_string(rules)?])?;
+        Ok(())
+    }
+
+    fn all_rules(&self) -> StorageResult<Vec<(String, Vec<Rule>)>> {
+        all(&self.conn(), "rules", "key")
+    }
+
+    fn count_duplicate(&self, messages: &[MessageKey], user: Option<&UserKey>, username: Option<&str>,
+                       now: DateTime<Utc>) -> StorageResult<()> {
+        let mut conn = self.conn();
+        // rolled back when dropped without a commit
+        let tx = conn.transaction()?;
+        for key in messages {
+            if let Some(value) = get_raw(&tx, "messages", "key", &blob(key)?)? {
+                let mut info = match serde_json::from_slice::<MessageInfo>(&value) {
+                    Ok(info) => info,
+                    Err(e) => {
+                        // moved out of the way once the transaction is over
+                        drop(tx);
+                        return Err(quarantine(&conn, "messages", "key", &blob(key)?, &value, e));
+                    }
+                };
+                info.count += 1;
+                put(&tx, "messages", key, &info)?;
+            }
+        }
+        if let Some(key) = user {
+            let previous = match get_raw(&tx, "users", "key", &blob(key)?)? {
+                Some(value) => match serde_json::from_slice::<TopUserValue>(&value) {
+                    Ok(previous) => Some(previous),
+                    Err(e) => {
+                        drop(tx);
+                        return Err(quarantine(&conn, "users", "key", &blob(key)?, &value, e));
+                    }
+                },
+                None => None,
+            };
+            put(&tx, "users", key, &counted_user(previous, username, now))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn quarantined(&self) -> StorageResult<Vec<Quarantined>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare("SELECT tbl, key, value FROM quarantine")?;
+        let records = statement.query_map([], |row| Ok(Quarantined {
+                                    table: row.get(0)?,
+                                    key: bytes_of(row.get(1)?),
+                                    value: bytes_of(row.get(2)?),
+                                }))?
+                               .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+}
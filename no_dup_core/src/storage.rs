@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+
+use crate::keys::KeyError;
+use crate::memory_storage::MemoryStorage;
+use crate::rules::Rule;
+use crate::settings::ChatSettings;
+use crate::sled_storage::SledStorage;
+use crate::sqlite_storage::SqliteStorage;
+use crate::store::{FileIdKey, ImageKey, ImageValue, MessageInfo, MessageKey, TopUserValue, UserKey};
+
+/// Why a [`Storage`] call failed.
+#[derive(Debug)]
+pub enum StorageError {
+    // a stored record could not be decoded, it was moved to the quarantine
+    Corrupt { table: String, key: Vec<u8> },
+    // the backend failed, e.g. on a disk error
+    Io(Box<dyn Error + Send + Sync>),
+    // a record could not be encoded
+    Serde(serde_json::Error),
+    // a key has no binary form, see `BinaryKey`
+    Key(KeyError),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Corrupt { table, key } =>
+                write!(f, "corrupt record {} in {}, moved to the quarantine", key.escape_ascii(), table),
+            StorageError::Io(e) => write!(f, "storage error: {}", e),
+            StorageError::Serde(e) => write!(f, "cannot encode record: {}", e),
+            StorageError::Key(e) => write!(f, "invalid key: {}", e),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Corrupt { .. } => None,
+            StorageError::Io(e) => Some(&**e),
+            StorageError::Serde(e) => Some(e),
+            StorageError::Key(e) => Some(e),
+        }
+    }
+}
+
+impl From<KeyError> for StorageError {
+    fn from(e: KeyError) -> Self {
+        StorageError::Key(e)
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError::Io(Box::new(e))
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Io(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Serde(e)
+    }
+}
+
+pub type StorageResult<T> = std::result::Result<T, StorageError>;
+
+/// Makes the new value of a record out of its current one, see
+/// [`Storage::update_message`].
+pub type Update<'a, V> = &'a mut dyn FnMut(Option<V>) -> Option<V>;
+
+/// A record that could not be decoded, kept as it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantined {
+    // the table or tree it was found in
+    pub table: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Where the detector keeps its records: messages, image hashes and file ids,
+/// the top board, and the settings and rules of chats. Listing methods take
+/// the chat to list, or `None` for every chat.
+///
+/// A record that cannot be decoded is moved to the quarantine, finding it
+/// fails with [`StorageError::Corrupt`] and listing skips it.
+pub trait Storage: Send + Sync {
+    fn find_message(&self, key: &MessageKey) -> StorageResult<Option<MessageInfo>>;
+    fn save_message(&self, key: &MessageKey, info: &MessageInfo) -> StorageResult<()>;
+    fn remove_message(&self, key: &MessageKey) -> StorageResult<()>;
+    fn messages(&self, chat_id: Option<&str>) -> StorageResult<Vec<(MessageKey, MessageInfo)>>;
+    /// Replaces a message with what `f` makes of it, in a single step that no
+    /// other write gets in between of. `f` gets `None` for a message never
+    /// seen, returns `None` to remove it, and is called again if the message
+    /// changed meanwhile. Returns what was stored.
+    fn update_message(&self, key: &MessageKey, f: Update<'_, MessageInfo>) -> StorageResult<Option<MessageInfo>>;
+
+    fn find_image(&self, key: &ImageKey) -> StorageResult<Option<ImageValue>>;
+    fn save_image(&self, key: &ImageKey, value: &ImageValue) -> StorageResult<()>;
+    fn remove_image(&self, key: &ImageKey) -> StorageResult<()>;
+    fn images(&self, chat_id: Option<&str>) -> StorageResult<Vec<(ImageKey, ImageValue)>>;
+
+    fn find_file(&self, key: &FileIdKey) -> StorageResult<Option<ImageValue>>;
+    fn save_file(&self, key: &FileIdKey, value: &ImageValue) -> StorageResult<()>;
+    fn remove_file(&self, key: &FileIdKey) -> StorageResult<()>;
+    fn files(&self, chat_id: Option<&str>) -> StorageResult<Vec<(FileIdKey, ImageValue)>>;
+
+    fn find_user(&self, key: &UserKey) -> StorageResult<Option<TopUserValue>>;
+    fn save_user(&self, key: &UserKey, value: &TopUserValue) -> StorageResult<()>;
+    fn remove_user(&self, key: &UserKey) -> StorageResult<()>;
+    fn users(&self, chat_id: Option<&str>) -> StorageResult<Vec<(UserKey, TopUserValue)>>;
+    /// Like [`Storage::update_message`], for a user on the top board.
+    fn update_user(&self, key: &UserKey, f: Update<'_, TopUserValue>) -> StorageResult<Option<TopUserValue>>;
+
+    fn find_settings(&self, chat_id: &str) -> StorageResult<Option<ChatSettings>>;
+    fn save_settings(&self, chat_id: &str, settings: &ChatSettings) -> StorageResult<()>;
+    fn remove_settings(&self, chat_id: &str) -> StorageResult<()>;
+    fn all_settings(&self) -> StorageResult<Vec<(String, ChatSettings)>>;
+
+    /// Rules of a chat, or of [`GLOBAL_RULES`](crate::rules::GLOBAL_RULES).
+    fn find_rules(&self, key: &str) -> StorageResult<Option<Vec<Rule>>>;
+    fn save_rules(&self, key: &str, rules: &[Rule]) -> StorageResult<()>;
+    fn all_rules(&self) -> StorageResult<Vec<(String, Vec<Rule>)>>;
+
+    /// Bumps the count of each of `messages` that exists, and of `user` on the
+    /// top board, all or nothing.
+    fn count_duplicate(&self, messages: &[MessageKey], user: Option<&UserKey>, username: Option<&str>,
+                       now: DateTime<Utc>) -> StorageResult<()>;
+
+    /// Records moved to the quarantine so far.
+    fn quarantined(&self) -> StorageResult<Vec<Quarantined>>;
+}
+
+// the top board record of a user after one more duplicate
+pub(crate) fn counted_user(previous: Option<TopUserValue>, username: Option<&str>, now: DateTime<Utc>) -> TopUserValue {
+    TopUserValue {
+        username: username.map(String::from),
+        count: previous.map_or(0, |v| v.count) + 1,
+        last_seen: Some(now),
+    }
+}
+
+/// Which [`Storage`] to use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageConfig {
+    // a sled database directory
+    Sled(String),
+    // an SQLite database file
+    Sqlite(String),
+    // nothing survives a restart, for tests
+    Memory,
+}
+
+impl StorageConfig {
+    /// The storage of this kind at `path`, ignored for memory.
+    pub fn with_path(kind: &str, path: &str) -> Result<Self> {
+        match kind.parse::<StorageKind>()? {
+            StorageKind::Sled => Ok(StorageConfig::Sled(String::from(path))),
+            StorageKind::Sqlite => Ok(StorageConfig::Sqlite(String::from(path))),
+            StorageKind::Memory => Ok(StorageConfig::Memory),
+        }
+    }
+
+    pub fn open(&self) -> Result<Arc<dyn Storage>> {
+        let storage: Arc<dyn Storage> = match self {
+            StorageConfig::Sled(path) => Arc::new(SledStorage::open(path)?),
+            StorageConfig::Sqlite(path) => Arc::new(SqliteStorage::open(path)?),
+            StorageConfig::Memory => Arc::new(MemoryStorage::default()),
+        };
+        Ok(storage)
+    }
+}
+
+impl fmt::Display for StorageConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageConfig::Sled(path) => write!(f, "sled database {}", path),
+            StorageConfig::Sqlite(path) => write!(f, "SQLite database {}", path),
+            StorageConfig::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+enum StorageKind {
+    Sled,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sled" => Ok(StorageKind::Sled),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(anyhow!("unknown storage {:?}, expected one of sled, sqlite, memory", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Occurrence;
+    use url::Url;
+
+    static TASKS: usize = 16;
+    static ROUNDS: usize = 50;
+
+    fn storages() -> Vec<Arc<dyn Storage>> {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        vec![Arc::new(MemoryStorage::default()),
+             Arc::new(SledStorage::new(&db).unwrap()),
+             Arc::new(SqliteStorage::open(":memory:").unwrap())]
+    }
+
+    fn message_key() -> MessageKey {
+        MessageKey { chat_id: String::from("1234"), url: Url::parse("https://example.com/a/b").unwrap() }
+    }
+
+    fn user_key() -> UserKey {
+        UserKey { chat_id: String::from("1234"), user_id: 42 }
+    }
+
+    fn occurrence(message_id: i32) -> Occurrence {
+        Occurrence { seen_at: Utc::now(), link: None, user_id: Some(42), username: None,
+                     message_id: Some(message_id) }
+    }
+
+    // runs `f` from TASKS tasks at once, ROUNDS times each
+    async fn hammer<F>(storage: &Arc<dyn Storage>, f: F)
+    where
+        F: Fn(&dyn Storage, usize) + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let tasks: Vec<_> = (0..TASKS).map(|task| {
+            let (storage, f) = (storage.clone(), f.clone());
+            tokio::task::spawn_blocking(move || {
+                for round in 0..ROUNDS {
+                    f(&*storage, task * ROUNDS + round);
+                }
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn counted_duplicates_add_up() {
+        for storage in storages() {
+            storage.save_message(&message_key(), &MessageInfo::new(message_key().url, occurrence(0))).unwrap();
+            hammer(&storage, |storage, _| {
+                storage.count_duplicate(&[message_key()], Some(&user_key()), Some("someone"), Utc::now()).unwrap();
+            }).await;
+
+            let total = (TASKS * ROUNDS) as u32;
+            assert_eq!(storage.find_message(&message_key()).unwrap().unwrap().count, total + 1);
+            assert_eq!(storage.find_user(&user_key()).unwrap().unwrap().count, total as i64);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn updates_of_the_same_message_are_never_lost() {
+        for storage in storages() {
+            hammer(&storage, |storage, i| {
+                storage.update_message(&message_key(), &mut |info| match info {
+                    Some(mut info) => {
+                        // the history is left alone, it would only slow the test down
+                        info.count += 1;
+                        Some(info)
+                    },
+                    None => Some(MessageInfo::new(message_key().url, occurrence(i as i32))),
+                }).unwrap();
+            }).await;
+
+            let info = storage.find_message(&message_key()).unwrap().unwrap();
+            assert_eq!(info.count as usize, TASKS * ROUNDS);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn updates_and_counts_of_the_same_records_mix() {
+        for storage in storages() {
+            storage.save_message(&message_key(), &MessageInfo::new(message_key().url, occurrence(0))).unwrap();
+            hammer(&storage, |storage, i| {
+                if i % 2 == 0 {
+                    storage.count_duplicate(&[message_key()], Some(&user_key()), None, Utc::now()).unwrap();
+                } else {
+                    storage.update_message(&message_key(), &mut |info| info.map(|mut info| {
+                        info.count += 1;
+                        info
+                    })).unwrap();
+                    storage.update_user(&user_key(), &mut |value| {
+                        Some(counted_user(value, None, Utc::now()))
+                    }).unwrap();
+                }
+            }).await;
+
+            let total = TASKS * ROUNDS;
+            assert_eq!(storage.find_message(&message_key()).unwrap().unwrap().count as usize, total + 1);
+            assert_eq!(storage.find_user(&user_key()).unwrap().unwrap().count as usize, total);
+        }
+    }
+}
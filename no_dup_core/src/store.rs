@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageInfo {
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    // number of times seen since the record was created, regardless of age,
+    // not counting duplicates whose originals were all deleted
+    pub count: u32,
+    #[serde(with = "url_serde")]
+    pub link: Option<Url>,
+    pub user_id: Option<i64>,
+    // records written before timestamps were tracked have none of these
+    #[serde(default)]
+    pub first_seen: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub history: Vec<Occurrence>,
+}
+
+/// A single time a message was seen in a chat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Occurrence {
+    pub seen_at: DateTime<Utc>,
+    #[serde(with = "url_serde")]
+    pub link: Option<Url>,
+    pub user_id: Option<i64>,
+    // display name of the sender at the time
+    #[serde(default)]
+    pub username: Option<String>,
+    // id of the message in its chat, to check whether it still exists
+    #[serde(default)]
+    pub message_id: Option<i32>,
+}
+
+impl MessageInfo {
+    pub fn new(url: Url, occurrence: Occurrence) -> Self {
+        MessageInfo {
+            url,
+            count: 1,
+            link: occurrence.link.clone(),
+            user_id: occurrence.user_id,
+            first_seen: Some(occurrence.seen_at),
+            last_seen: Some(occurrence.seen_at),
+            history: vec![occurrence],
+        }
+    }
+
+    pub fn record(&mut self, occurrence: Occurrence) {
+        self.count += 1;
+        self.first_seen.get_or_insert(occurrence.seen_at);
+        self.last_seen = Some(occurrence.seen_at);
+        self.history.push(occurrence);
+    }
+
+    /// Adds an occurrence without counting it, for duplicates that are only
+    /// counted once an original of them is known to still exist.
+    pub fn remember(&mut self, occurrence: Occurrence) {
+        self.last_seen = Some(occurrence.seen_at);
+        self.history.push(occurrence);
+    }
+
+    /// Drops the occurrence with `message_id` from the history, returns whether
+    /// there was one.
+    pub fn forget(&mut self, message_id: i32) -> bool {
+        let len = self.history.len();
+        self.history.retain(|o| o.message_id != Some(message_id));
+        self.history.len() != len
+    }
+
+    /// Occurrences seen at or after `since`, oldest first.
+    pub fn occurrences_since(&self, since: DateTime<Utc>) -> Vec<&Occurrence> {
+        self.history.iter().filter(|o| o.seen_at >= since).collect()
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageKey {
+    pub chat_id: String,
+    #[serde(with = "url_serde")]
+    pub url: Url
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageKey {
+    pub chat_id: String,
+    pub hash_str: String
+}
+
+// Telegram's identifier of a file, the same for every re-send of it
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileIdKey {
+    pub chat_id: String,
+    pub file_unique_id: String,
+}
+
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct ImageValue {
+    pub message: MessageKey,
+    pub timestamp: DateTime<Utc>,
+    // Telegram's file_id of the image, so it can be downloaded again to re-hash it
+    #[serde(default)]
+    pub file_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct UserKey {
+    pub chat_id: String,
+    pub user_id: i64
+}
+
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct TopUserValue {
+    pub username: Option<String>,
+    pub count: i64,
+    // last time the user sent a duplicate, unknown for older records
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,134 @@
+use chrono::{DateTime, Utc};
+
+use crate::detector::Duplicate;
+use crate::store::Occurrence;
+
+pub static FORWARD_TEMPLATE: &str = "你火星了！这条消息是第{count}次来到本群了，快去爬楼：{first_link}";
+pub static LINK_TEMPLATE: &str = "你火星了！这个链接是第{count}次来到本群了，快去爬楼：{first_link}";
+pub static IMAGE_TEMPLATE: &str = "你火星了！这个图片是第{count}次来到本群了，快去爬楼：{first_link}";
+pub static MEDIA_TEMPLATE: &str = "你火星了！这个{media}是第{count}次来到本群了，快去爬楼：{first_link}";
+
+/// Placeholders a template may use:
+///  - `{count}`: how many times it has been seen within the window,
+///  - `{first_link}`: link to the earlier message that still exists,
+///  - `{first_user}`: who sent that message,
+///  - `{age}`: how long ago that message was sent,
+///  - `{url}`: what the duplicate was recognized as,
+///  - `{media}`: what kind of thing it is, e.g. 视频.
+pub const PLACEHOLDERS: &[&str] = &["{count}", "{first_link}", "{first_user}", "{age}", "{url}", "{media}"];
+
+/// Fills in the placeholders of `template` for `duplicate`, whose earlier
+/// occurrence `original` is the one to point at. The template is read once,
+/// so that a placeholder in a filled in value, e.g. a user name, stays as it
+/// is. Unknown placeholders are left as they are too.
+pub fn render(template: &str, duplicate: &Duplicate, original: &Occurrence, now: DateTime<Utc>) -> String {
+    substitute(template, |placeholder| {
+        let value = match placeholder {
+            "{count}" => duplicate.count.to_string(),
+            "{first_link}" => original.link.as_ref().map_or(String::from("private chat"), |url| url.to_string()),
+            "{first_user}" => original.username.clone().unwrap_or_else(|| String::from("某位群友")),
+            "{age}" => format_age(now - original.seen_at),
+            "{url}" => duplicate.url.to_string(),
+            "{media}" => String::from(duplicate.kind.name()),
+            _ => return None,
+        };
+        Some(value)
+    })
+}
+
+/// Placeholders of `template` that are not in [`PLACEHOLDERS`].
+pub fn unknown_placeholders(template: &str) -> Vec<String> {
+    let mut unknown = vec![];
+    substitute(template, |placeholder| {
+        if !PLACEHOLDERS.contains(&placeholder) {
+            unknown.push(String::from(placeholder));
+        }
+        None
+    });
+    unknown
+}
+
+// Replaces every `{name}` in `template` with what `value` gives for it, or
+// keeps it if that is `None`.
+fn substitute<F: FnMut(&str) -> Option<String>>(template: &str, mut value: F) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find(['{', '}']) {
+            Some(end) if after[end..].starts_with('}') => {
+                let placeholder = &rest[start..start + end + 2];
+                match value(placeholder) {
+                    Some(v) => out.push_str(&v),
+                    None => out.push_str(placeholder),
+                }
+                rest = &after[end + 1..];
+            },
+            // not a placeholder, e.g. `{{count}`
+            _ => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// e.g. 3小时前
+fn format_age(age: chrono::Duration) -> String {
+    if age.num_days() > 0 {
+        format!("{}天前", age.num_days())
+    } else if age.num_hours() > 0 {
+        format!("{}小时前", age.num_hours())
+    } else if age.num_minutes() > 0 {
+        format!("{}分钟前", age.num_minutes())
+    } else {
+        String::from("刚刚")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::DuplicateKind;
+    use chrono::Duration;
+    use url::Url;
+
+    fn duplicate() -> Duplicate {
+        Duplicate { url: Url::parse("https://example.com/a").unwrap(), kind: DuplicateKind::Link, count: 3,
+                    originals: vec![] }
+    }
+
+    fn original(username: &str, now: DateTime<Utc>) -> Occurrence {
+        Occurrence { seen_at: now - Duration::hours(2), link: Some(Url::parse("https://t.me/c/1/2").unwrap()),
+                     user_id: Some(42), username: Some(String::from(username)), message_id: Some(2) }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let now = Utc::now();
+        let rendered = render("{first_user} 在{age}发过第{count}次的{media} {url}：{first_link}",
+                              &duplicate(), &original("someone", now), now);
+        assert_eq!(rendered, "someone 在2小时前发过第3次的链接 https://example.com/a：https://t.me/c/1/2");
+        assert_eq!(render(LINK_TEMPLATE, &duplicate(), &original("a", now), now),
+                   "你火星了！这个链接是第3次来到本群了，快去爬楼：https://t.me/c/1/2");
+    }
+
+    #[test]
+    fn values_are_not_filled_in_again() {
+        let now = Utc::now();
+        let rendered = render("{first_user}: {count}", &duplicate(), &original("{first_link} {count}", now), now);
+        assert_eq!(rendered, "{first_link} {count}: 3");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        let now = Utc::now();
+        let rendered = render("{{count}} {nope} {count", &duplicate(), &original("a", now), now);
+        assert_eq!(rendered, "{3} {nope} {count");
+        assert_eq!(unknown_placeholders("{{count}} {nope} {count"), vec!["{nope}"]);
+        assert!(unknown_placeholders(MEDIA_TEMPLATE).is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BinaryHeap;
+
+use chrono::Utc;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::storage::Storage;
+use crate::store::{MessageKey, TopUserValue, UserKey};
+
+/// Resets the count of every user in the chat to zero, returns how many users
+/// were reset.
+pub fn reset_top_board(storage: &dyn Storage, chat_id: &str) -> usize {
+    let users = match storage.users(Some(chat_id)) {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("top database error {:?} when listing chat {:?}", &e, chat_id);
+            return 0;
+        }
+    };
+
+    let mut count = 0;
+    for (iter_key, _) in users {
+        // a duplicate counted meanwhile is reset along with the rest
+        let reset = storage.update_user(&iter_key, &mut |value| {
+            value.map(|value| TopUserValue{
+                username: Some(value.username.unwrap_or_else(|| iter_key.user_id.to_string())),
+                count: 0,
+                last_seen: value.last_seen,
+            })
+        });
+
+        match reset {
+            Ok(Some(_)) => count += 1,
+            Ok(None) => (),
+            Err(e) => warn!("top database error {:?} when resetting key {:?}", &e, &iter_key),
+        }
+    }
+    count
+}
+
+/// Counts a duplicate that has an original left: bumps the count of each of
+/// `urls` in the chat, and of the sender on the top board, all or nothing.
+pub fn count_duplicate(storage: &dyn Storage, chat_id: &str, urls: &[Url],
+                       user_id: &Option<i64>, username: &Option<String>) -> bool {
+    let message_keys: Vec<MessageKey> = urls.iter()
+        .map(|url| MessageKey{chat_id: String::from(chat_id), url: url.clone()})
+        .collect();
+    let user_key = user_id.map(|user_id| UserKey{chat_id: String::from(chat_id), user_id});
+
+    let result = storage.count_duplicate(&message_keys, user_key.as_ref(), username.as_deref(), Utc::now());
+    if let Err(e) = result {
+        warn!("database error {:?} when counting {:?} against {:?}", &e, &message_keys, &user_key);
+        false
+    } else {
+        info!("Counted {} duplicates against {:?}", message_keys.len(), &user_key);
+        true
+    }
+}
+
+/// Most duplicated messages in the chat, as `(count, link)` with the highest
+/// count first.
+pub fn topics(storage: &dyn Storage, chat_id: &str) -> Vec<(u32, String)> {
+    let messages = match storage.messages(Some(chat_id)) {
+        Ok(messages) => messages,
+        Err(e) => {
+            warn!("database error {:?} when listing chat {:?}", &e, chat_id);
+            return vec![];
+        }
+    };
+
+    let mut heap = BinaryHeap::new();
+    for (_, value) in messages {
+        let link = match value.link.clone() {
+            Some(url) => url.to_string(),
+            None => String::from("Link not available")
+        };
+        heap.push((value.count, link));
+    }
+    let mut ans = heap.into_sorted_vec();
+    ans.reverse();
+    ans
+}
+
+/// Users with most duplicated messages in the chat, as `(count, username)`
+/// with the highest count first. Users with a zero count are left out.
+pub fn top_board(storage: &dyn Storage, chat_id: &str) -> Vec<(i64, String)> {
+    let users = match storage.users(Some(chat_id)) {
+        Ok(users) => users,
+        Err(e) => {
+            warn!("top database error {:?} when listing chat {:?}", &e, chat_id);
+            return vec![];
+        }
+    };
+
+    let mut heap = BinaryHeap::new();
+    for (top_key, value) in users {
+        let username = match value.username.clone() {
+            Some(user_name) => user_name,
+            None => top_key.user_id.to_string()
+        };
+
+        if value.count > 0 {
+            heap.push((value.count, username));
+        }
+    }
+    let mut ans = heap.into_sorted_vec();
+    ans.reverse();
+    ans
+}
+
+/// Number of duplicates sent by a user in the chat, `None` if never recorded.
+pub fn user_count(storage: &dyn Storage, chat_id: &str, user_id: i64) -> Option<i64> {
+    let key = UserKey{
+        chat_id: String::from(chat_id),
+        user_id,
+    };
+    match storage.find_user(&key) {
+        Err(e) => {
+            warn!("top board database get error {:?} when looking for key {:?}", &e, &key);
+            None
+        },
+        Ok(Some(value)) => {
+            info!("In top db, finding '{:?}' returns '{:?}'", &key, &value);
+            Some(value.count)
+        },
+        Ok(None) => None
+    }
+}
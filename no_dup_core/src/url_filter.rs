@@ -0,0 +1,145 @@
+use tracing::{debug, info};
+use url::Url;
+
+use crate::rules::{RuleAction, RuleSet};
+
+/// Links whose path and query are at most this long, e.g. `https://google.com/`,
+/// are too generic to be duplicates, see 特殊性原则 in the PRD.
+pub static SHORT_PATH_LEN: usize = 6;
+
+/// Parses a message text that consists of nothing but a URL.
+pub fn get_url(text: &str) -> Option<Url> {
+    Url::parse(text).ok()
+}
+
+/// Parses a link found inside a message, which may come without a scheme,
+/// e.g. `example.com/page`.
+pub fn parse_link(link: &str) -> Option<Url> {
+    let link = link.trim();
+    if link.contains("://") {
+        Url::parse(link).ok()
+    } else {
+        Url::parse(&format!("https://{}", link)).ok()
+    }
+}
+
+/// Whether the pathname plus search of `url` is at most `max_len` long.
+pub fn is_short_path(url: &Url, max_len: usize) -> bool {
+    let search_len = url.query().map_or(0, |q| q.len() + 1);
+    url.path().len() + search_len <= max_len
+}
+
+/// Drops URLs that should never be reported as duplicates in `chat_id`, and
+/// normalizes the ones the rules ask for. Links with a path and query of at
+/// most `short_path_len` are dropped too, unless a rule tracks them.
+pub fn filter_url(chat_id: &str, url: Option<Url>, rules: &RuleSet, short_path_len: usize) -> Option<Url> {
+    let mut url = url?;
+    match rules.decide(chat_id, &url) {
+        Some(RuleAction::Track) => {
+            debug!("Url {} is always tracked", url);
+            return Some(url);
+        },
+        Some(RuleAction::Ignore) => {
+            info!("Url {} gets filtered out by a rule", url);
+            return None;
+        },
+        Some(RuleAction::Normalize) => {
+            // Remove params
+            url.set_query(None);
+            url.set_fragment(None);
+        },
+        None => {}
+    }
+    if is_short_path(&url, short_path_len) {
+        info!("Url {} gets filtered out since its path is too short", url);
+        return None;
+    }
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rule;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn filter(s: &str, rules: &RuleSet) -> Option<Url> {
+        filter_url("1234", Some(url(s)), rules, SHORT_PATH_LEN)
+    }
+
+    #[test]
+    fn short_path_counts_path_and_search() {
+        assert!(is_short_path(&url("https://google.com/"), 6));
+        assert!(is_short_path(&url("https://google.com/abcde"), 6));
+        assert!(!is_short_path(&url("https://google.com/abcdef"), 6));
+        assert!(is_short_path(&url("https://google.com/?q=1"), 6));
+        assert!(!is_short_path(&url("https://google.com/a?q=12"), 6));
+        // the fragment is not part of it
+        assert!(is_short_path(&url("https://google.com/#something-long"), 6));
+    }
+
+    #[test]
+    fn short_paths_are_filtered_out() {
+        let rules = RuleSet::default();
+        assert_eq!(filter("https://google.com/", &rules), None);
+        assert_eq!(filter("https://example.com/news", &rules), None);
+        assert!(filter("https://example.com/news/2021/some-story", &rules).is_some());
+    }
+
+    #[test]
+    fn short_path_len_is_configurable() {
+        let rules = RuleSet::default();
+        let link = "https://example.com/news";
+        assert_eq!(filter_url("1234", Some(url(link)), &rules, 10), None);
+        assert!(filter_url("1234", Some(url(link)), &rules, 2).is_some());
+        // the root path is one character, so zero turns the exemption off
+        assert!(filter_url("1234", Some(url("https://google.com/")), &rules, 0).is_some());
+    }
+
+    #[test]
+    fn tracked_urls_skip_the_exemption() {
+        let rules = RuleSet {
+            chat: vec![Rule::new("google.com", RuleAction::Track)],
+            global: vec![],
+        };
+        assert!(filter("https://google.com/", &rules).is_some());
+    }
+
+    #[test]
+    fn default_rules_skip_own_messages_and_invitations() {
+        let rules = RuleSet { chat: vec![], global: crate::rules::default_global_rules() };
+        assert_eq!(filter("https://t.me/c/1234/56", &rules), None);
+        assert!(filter("https://t.me/c/5678/56", &rules).is_some());
+        assert_eq!(filter("https://t.me/joinchat/AbCdEfGh", &rules), None);
+        assert!(filter("https://t.me/somechannel/56", &rules).is_some());
+    }
+
+    #[test]
+    fn mirror_prefixes_of_patterns_are_dropped() {
+        let rules = RuleSet {
+            chat: vec![Rule::new("www.example.com", RuleAction::Ignore),
+                       Rule::new("https://m.example.org/news/*", RuleAction::Ignore)],
+            global: vec![],
+        };
+        assert_eq!(rules.chat[0].pattern, "example.com");
+        assert_eq!(filter("https://example.com/news/2021/some-story", &rules), None);
+        assert_eq!(filter("https://example.org/news/2021/some-story", &rules), None);
+        assert_eq!(Rule::new("www.com", RuleAction::Ignore).pattern, "www.com");
+    }
+
+    #[test]
+    fn hosts_match_in_any_case_paths_only_in_theirs() {
+        let rules = RuleSet {
+            chat: vec![Rule::new("HTTPS://Example.COM", RuleAction::Ignore),
+                       Rule::new("Example.org/Watch/ABC*", RuleAction::Ignore)],
+            global: vec![],
+        };
+        assert_eq!(rules.chat[1].pattern, "example.org/Watch/ABC*");
+        assert_eq!(filter("https://EXAMPLE.com/news/2021/some-story", &rules), None);
+        assert_eq!(filter("https://example.ORG/Watch/ABCdef", &rules), None);
+        assert!(filter("https://example.org/watch/abcdef", &rules).is_some());
+    }
+}
@@ -0,0 +1,251 @@
+use std::env;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use tracing::{warn, Level};
+
+use no_dup_core::{ChatSettings, GcConfig, HashAlgorithm, HashConfig, StorageConfig};
+
+/// Config file read when none is given on the command line, if it exists.
+pub static CONFIG_PATH: &str = "no_dup_bot.toml";
+
+// Everything the bot stores, in trees of a single sled database
+static DB_PATH: &str = "no_dup_db";
+
+/// Settings of a deployment, read from a TOML file. Environment variables
+/// override the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // where records are kept: sled, sqlite or memory
+    pub storage: String,
+    // directory of the sled database, or the SQLite file
+    pub data_dir: String,
+    // users allowed to delete the bot's replies and to change shared rules
+    pub admins: Vec<i64>,
+    // token from @BotFather, $TELOXIDE_TOKEN is used if not set
+    pub token: Option<String>,
+    // one of error, warn, info, debug and trace
+    pub log_level: String,
+    // settings of chats that did not configure anything with /config
+    #[serde(deserialize_with = "checked_settings")]
+    pub defaults: ChatSettings,
+    pub gc: GcSection,
+    pub hash: HashSection,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcSection {
+    pub interval_minutes: Option<u64>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HashSection {
+    pub algorithm: Option<String>,
+    pub size: Option<u32>,
+}
+
+// Goes through ChatSettings::set like /config does, so that unknown keys and
+// values out of range are refused
+fn checked_settings<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<ChatSettings, D::Error> {
+    let mut settings = ChatSettings::default();
+    for (key, value) in toml::value::Table::deserialize(deserializer)? {
+        let value = match value {
+            toml::Value::String(s) => s,
+            v => v.to_string(),
+        };
+        settings.set(&key, &value).map_err(serde::de::Error::custom)?;
+    }
+    Ok(settings)
+}
+
+// A GC interval of `minutes`, which cannot be 0
+fn gc_interval(minutes: u64) -> Result<std::time::Duration> {
+    match minutes.checked_mul(60) {
+        Some(0) => Err(anyhow!("the gc interval must be at least a minute")),
+        Some(secs) => Ok(std::time::Duration::from_secs(secs)),
+        None => Err(anyhow!("the gc interval of {} minutes is too long", minutes)),
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            storage: String::from("sled"),
+            data_dir: String::from(DB_PATH),
+            admins: Vec::new(),
+            token: None,
+            log_level: String::from("info"),
+            defaults: ChatSettings::default(),
+            gc: GcSection::default(),
+            hash: HashSection::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path`, or [`CONFIG_PATH`] if there is such a file, and applies
+    /// the environment variables on top. Numbers in the environment are only
+    /// checked once logging is set up, by the methods below.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = path.or_else(|| Some(Path::new(CONFIG_PATH)).filter(|p| p.exists()));
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))?;
+                Self::parse(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            },
+            None => Config::default(),
+        };
+        if let Ok(v) = env::var("NO_DUP_BOT_STORAGE") {
+            config.storage = v;
+        }
+        if let Ok(v) = env::var("NO_DUP_BOT_DB_PATH") {
+            config.data_dir = v;
+        }
+        if let Ok(v) = env::var("NO_DUP_BOT_ADMIN") {
+            // ids are separated by colons, anything else is left out
+            config.admins = v.split(':').filter_map(|id| id.parse().ok()).collect();
+        }
+        if let Ok(v) = env::var("TELOXIDE_TOKEN") {
+            config.token = Some(v);
+        }
+        if let Ok(v) = env::var("NO_DUP_BOT_LOG_LEVEL") {
+            config.log_level = v;
+        }
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn log_level(&self) -> Result<Level> {
+        self.log_level.parse().map_err(|_| anyhow!("unknown log level {:?}", &self.log_level))
+    }
+
+    pub fn storage_config(&self) -> Result<StorageConfig> {
+        StorageConfig::with_path(&self.storage, &self.data_dir)
+    }
+
+    // Defaults for chats that did not configure anything with /config
+    pub fn default_settings(&self) -> ChatSettings {
+        let mut settings = self.defaults.clone();
+        for (env_key, key) in [("NO_DUP_BOT_WINDOW_HOURS", "window_hours"),
+                               ("NO_DUP_BOT_RETENTION_DAYS", "retention_days")] {
+            if let Ok(v) = env::var(env_key) {
+                if let Err(e) = settings.set(key, &v) {
+                    warn!("${} is ignored: {}", env_key, e);
+                }
+            }
+        }
+        settings
+    }
+
+    pub fn gc_config(&self) -> Result<GcConfig> {
+        let mut config = GcConfig::default();
+        if let Some(minutes) = self.gc.interval_minutes {
+            config.interval = gc_interval(minutes)?;
+        }
+        if let Ok(v) = env::var("NO_DUP_BOT_GC_INTERVAL_MINUTES") {
+            match v.parse::<u64>() {
+                Ok(minutes) => config.interval = gc_interval(minutes)
+                    .map_err(|e| anyhow!("$NO_DUP_BOT_GC_INTERVAL_MINUTES: {}", e))?,
+                Err(_) => warn!("$NO_DUP_BOT_GC_INTERVAL_MINUTES is not a number of minutes: {:?}", &v)
+            }
+        }
+        config.dry_run = match env::var("NO_DUP_BOT_GC_DRY_RUN") {
+            Ok(v) => v != "0" && v != "false",
+            Err(_) => self.gc.dry_run,
+        };
+        Ok(config)
+    }
+
+    // How images are hashed, changing it needs a `no_dup_bot rehash`. A typo
+    // would silently stop matching every stored hash, so it is refused.
+    pub fn hash_config(&self) -> Result<HashConfig> {
+        let mut config = HashConfig::default();
+        if let Some(v) = env::var("NO_DUP_BOT_HASH_ALGORITHM").ok().or_else(|| self.hash.algorithm.clone()) {
+            config.algorithm = v.parse::<HashAlgorithm>()?;
+        }
+        if let Some(size) = self.hash.size {
+            config.size = size;
+        }
+        if let Ok(v) = env::var("NO_DUP_BOT_HASH_SIZE") {
+            config.size = v.parse::<u32>().map_err(|_| anyhow!("$NO_DUP_BOT_HASH_SIZE is not a number: {:?}", &v))?;
+        }
+        if !HashConfig::SIZES.contains(&config.size) {
+            return Err(anyhow!("the hash size must be between {} and {}, not {}",
+                               HashConfig::SIZES.start(), HashConfig::SIZES.end(), config.size));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_keys_take_the_defaults() {
+        let config = Config::parse("admins = [1, 2]\n[defaults]\nwindow_hours = 12\n").unwrap();
+        assert_eq!(config.admins, vec![1, 2]);
+        assert_eq!(config.data_dir, DB_PATH);
+        assert_eq!(config.defaults.window_hours, 12);
+        assert_eq!(config.defaults.threshold, ChatSettings::default().threshold);
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(Config::parse("admin = [1]\n").is_err());
+        assert!(Config::parse("[gc]\ninterval = 5\n").is_err());
+    }
+
+    #[test]
+    fn defaults_are_checked() {
+        let config = Config::parse("[defaults]\nrobust_matching = true\nlink_template = \"a\\nb\"\n").unwrap();
+        assert!(config.defaults.robust_matching);
+        assert_eq!(config.defaults.link_template, "a\nb");
+        assert!(Config::parse("[defaults]\nwindow_hour = 12\n").is_err());
+        assert!(Config::parse("[defaults]\nwindow_hours = 0\n").is_err());
+        assert!(Config::parse("[defaults]\nretention_days = 100000000000\n").is_err());
+    }
+
+    #[test]
+    fn sections_are_read() {
+        let config = Config::parse("log_level = \"debug\"\nstorage = \"sqlite\"\ndata_dir = \"bot.sqlite\"\n\
+                                    [gc]\ninterval_minutes = 5\n[hash]\nsize = 16\n").unwrap();
+        assert_eq!(config.log_level().unwrap(), Level::DEBUG);
+        assert_eq!(config.storage_config().unwrap().to_string(),
+                   StorageConfig::with_path("sqlite", "bot.sqlite").unwrap().to_string());
+        assert_eq!(config.gc.interval_minutes, Some(5));
+        assert_eq!(config.hash.size, Some(16));
+    }
+
+    #[test]
+    fn gc_intervals_are_checked() {
+        let config = Config::parse("[gc]\ninterval_minutes = 5\n").unwrap();
+        assert_eq!(config.gc_config().unwrap().interval, std::time::Duration::from_secs(300));
+        assert!(Config::parse("[gc]\ninterval_minutes = 0\n").unwrap().gc_config().is_err());
+        let huge = format!("[gc]\ninterval_minutes = {}\n", i64::MAX);
+        assert!(Config::parse(&huge).unwrap().gc_config().is_err());
+    }
+
+    #[test]
+    fn hash_settings_are_checked() {
+        let config = Config::parse("[hash]\nalgorithm = \"double-gradient\"\nsize = 16\n").unwrap();
+        let hash = config.hash_config().unwrap();
+        assert_eq!(hash.algorithm, HashAlgorithm::DoubleGradient);
+        assert_eq!(hash.size, 16);
+        assert_eq!(Config::default().hash_config().unwrap(), HashConfig::default());
+        assert!(Config::parse("[hash]\nalgorithm = \"gradiant\"\n").unwrap().hash_config().is_err());
+        for size in [0, 3, 65, 100000] {
+            let config = Config::parse(&format!("[hash]\nsize = {}\n", size)).unwrap();
+            assert!(config.hash_config().is_err(), "size {} is accepted", size);
+        }
+    }
+}
@@ -1,31 +1,37 @@
 use teloxide::payloads::SendMessageSetters;
-use teloxide::{prelude::*, net::Download, types::File as TgFile, types::PhotoSize};
+use teloxide::{prelude::*, net::Download, types::File as TgFile, types::MessageEntityKind, types::PhotoSize, types::User};
 use teloxide::{RequestError, ApiError};
 use teloxide::utils::command::BotCommand;
 
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::{Mutex, MutexGuard};
 
-use url::Url;
-use serde::{Deserialize, Serialize};
-
-use std::io::{Error, ErrorKind};
-
-use anyhow::Result;
-use img_hash::ImageHash;
-// use bytes::{Bytes, BytesMut, Buf, BufMut};
+use anyhow::{anyhow, Result};
 use bytes::BufMut;
-use std::collections::BinaryHeap;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use std::path::{Path, PathBuf};
+use once_cell::sync::{Lazy, OnceCell};
+use tracing::{debug, info, span, warn, Level, Instrument};
 
-use std::env;
-use once_cell::sync::OnceCell;
-use tracing::{debug, debug_span, info, span, warn, trace, Level, Instrument};
-use tracing_subscriber;
+use no_dup_core::{template, clean_chat_id, dump, layout, spawn_gc, ChatSettings, DuplicateDetector, MessageKey, Rule, RuleAction, GLOBAL_RULES, Duplicate, ForwardOrigin, IncomingMessage, MediaKind, Occurrence, Sender, Storage, Verdict};
 
-static BOT_NAME: &str = "no_dup_bot";
 static ADMIN: OnceCell<HashSet<i64>> = OnceCell::new();
-static TIME_OUT_DAYS: i64 = 10;
+// the bot itself, as told by getMe at startup
+static ME: OnceCell<User> = OnceCell::new();
+
+mod config;
+use config::Config;
+use structopt::StructOpt;
+
+// Username of the bot, needed to parse commands addressed to it
+fn bot_name() -> &'static str {
+    ME.get().and_then(|me| me.username.as_deref()).unwrap_or_default()
+}
+
+fn is_me(user: &User) -> bool {
+    ME.get().is_some_and(|me| me.id == user.id)
+}
 
 
 #[derive(BotCommand, Debug)]
@@ -43,6 +49,18 @@ enum Command {
     Me,
     #[command(description = "Reset the top record for the current chat")]
     ResetTop,
+    #[command(description = "Show or change settings of the current chat: get [key], set <key> <value>, reset")]
+    Config(String),
+    #[command(description = "Never report links matching a domain or pattern, add -g for all chats")]
+    Ignore(String),
+    #[command(description = "Always report links matching a domain or pattern, add -g for all chats")]
+    Track(String),
+    #[command(description = "Compare links matching a domain or pattern without their query, add -g for all chats")]
+    Normalize(String),
+    #[command(description = "Remove the rule for a domain or pattern, add -g for all chats")]
+    Unignore(String),
+    #[command(description = "Show the link rules of the current chat")]
+    Rules,
 }
 
 // returns true if we can get where this message is from, and it matches the
@@ -73,10 +91,9 @@ fn come_from_original_author(cx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool
 }
 
 fn is_admin(cx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
-    let admin_db = ADMIN.get().unwrap().clone();
+    let admin_db = ADMIN.get().unwrap();
 
     if let Some(user) = cx.update.from() {
-        // dbg!(user);
         if admin_db.contains(&user.id) {
             info!("Admin {:?} confirmed", &user.id);
             return true
@@ -86,7 +103,7 @@ fn is_admin(cx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
 }
 
 fn allows_delete(cx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
-    if is_admin(&cx) {
+    if is_admin(cx) {
         info!("Deleting message as directed by admin");
         return true
     }
@@ -99,16 +116,9 @@ fn allows_delete(cx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
 
 // Delete the replied message
 fn reply_to_bot(cx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
-    if let Some(message) = cx.update.reply_to_message() {
-        if let Some(usr) = message.from() {
-            if let Some(username) = &usr.username {
-                if username.eq(BOT_NAME) {
-                    return true
-                }
-            }
-        }
-    }
-    false
+    cx.update.reply_to_message()
+             .and_then(|message| message.from())
+             .is_some_and(is_me)
 }
 
 // Delete the replied message
@@ -117,14 +127,12 @@ async fn delete_replied_msg(cx: &UpdateWithCx<AutoSend<Bot>, Message>)
     match cx.update.reply_to_message() {
         Some(message) => {
             if let Some(usr) = message.from() {
-                if let Some(username) = &usr.username {
-                    if username.eq(BOT_NAME) {
-                        info!("Start deleting message");
-                        if allows_delete(cx) {
-                            cx.requester
-                              .delete_message(cx.update.chat_id(), message.id)
-                              .await?;
-                        }
+                if is_me(usr) {
+                    info!("Start deleting message");
+                    if allows_delete(cx) {
+                        cx.requester
+                          .delete_message(cx.update.chat_id(), message.id)
+                          .await?;
                     }
                 }
             } else {
@@ -132,235 +140,25 @@ async fn delete_replied_msg(cx: &UpdateWithCx<AutoSend<Bot>, Message>)
             }
         }
         None => {
-            // info!("Use this command in a reply to another message!");
             cx.reply_to("Please reply to a message sent by the bot!").send().await?;
         }
     }
     Ok(())
 }
 
-use chrono::{DateTime, Duration, Utc};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MessageInfo {
-    #[serde(with = "url_serde")]
-    url: Url,
-    count: u32,
-    #[serde(with = "url_serde")]
-    link: Option<Url>,
-    user_id: Option<i64>,
-}
-
-#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
-pub struct MessageKey {
-    chat_id: String,
-    #[serde(with = "url_serde")]
-    url: Url
-}
-
-#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
-pub struct ImageKey {
-    chat_id: String,
-    hash_str: String
-}
-
-#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
-pub struct ImageValue {
-    message: MessageKey,
-    timestamp: DateTime<Utc>
-}
-
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-pub struct UserKey {
-    chat_id: String,
-    user_id: i64
-}
-
-#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
-pub struct TopUserValue {
-    username: Option<String>,
-    count: i64
-}
-
-impl PartialEq for MessageKey {
-    fn eq(&self, other: &Self) -> bool {
-        self.chat_id.eq(&other.chat_id) && self.url.eq(&other.url)
-    }
-}
-
-impl Eq for MessageKey {}
-
-pub trait KVStore {
-    fn init(file_path: &str) -> Self;
-    fn save(&self, k: &MessageKey, v: &MessageInfo) -> bool;
-    fn find(&self, k: &MessageKey) -> Option<MessageInfo>;
-    fn delete(&self, k: &MessageKey) -> bool;
-}
-
-pub struct MyDB {
-    db: sled::Db,
-}
-
-impl KVStore for MyDB {
-    fn init(file_path: &str) -> Self {
-        MyDB { db: sled::open(file_path).unwrap()}
-    }
-
-    fn save(&self, k: &MessageKey, v: &MessageInfo) -> bool {
-        let serialized_k = serde_json::to_string(&k).unwrap();
-        let serialized_v = serde_json::to_string(&v).unwrap();
-
-        if self.db.insert(serialized_k.as_bytes(), serialized_v.as_bytes()).is_err() {
-            warn!("database seve error when saving key {:?} with value {:?}", &k, &v);
-            false
-        } else {
-            true
-        }
-    }
-
-    fn find(&self, k: &MessageKey) -> Option<MessageInfo> {
-        let serialized_k = serde_json::to_string(&k).unwrap();
-        match self.db.get(serialized_k.as_bytes()) {
-            Ok(Some(v)) => {
-                let result = String::from_utf8(v.to_vec()).unwrap();
-                info!("Finding '{:?}' returns '{}'", k, result);
-                let result: MessageInfo = serde_json::from_str(&result).unwrap();
-                Some(result)
-            },
-            Ok(None) => {
-                info!("Message '{:?}' is not found", k);
-                None
-            },
-            Err(e) => {
-                warn!("Error retrieving value for {:?}: {}", k, e);
-                None
-            }
-        }
-    }
-
-    fn delete(&self, k: &MessageKey) -> bool {
-        let serialized_k = serde_json::to_string(&k).unwrap();
-        self.db.remove(serialized_k.as_bytes()).is_ok()
-    }
-}
-
-impl MyDB {
-    pub fn scan_prefix<P>(&self, prefix: P) -> sled::Iter
-    where
-        P: AsRef<[u8]>,
-    {
-        self.db.scan_prefix(prefix)
-    }
-}
-
 fn get_chat_id(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> String {
-    let id = ctx.update.chat_id();
-    let id_str = id.to_string();
-    id_str.strip_prefix("-100")
-          .map_or(id_str.clone(),
-                  |id| String::from(id))
-}
-
-fn get_msg_link(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> Option<Url> {
-    if ctx.update.chat.is_private() {
-        return None;
-    }
-    let id = ctx.update.id;
-    let url = match ctx.update.chat.username() {
-            // If it's public group (i.e. not DM, not private group), we can produce
-            // "normal" t.me link (accesible to everyone).
-            Some(username) => format!("https://t.me/{0}/{1}/", username, id),
-            // For private groups we produce "private" t.me/c links. These are only
-            // accesible to the group members.
-            None => format!("https://t.me/c/{0}/{1}/", get_chat_id(&ctx), id),
-        };
-    Some(Url::parse(&url).unwrap())
+    clean_chat_id(ctx.update.chat_id())
 }
 
-fn get_forward_msg_link(message: &UpdateWithCx<AutoSend<Bot>, Message>) -> Option<Url> {
-    let chat = message.update.forward_from_chat()?;
-    debug!("chat.username() is {:?}", chat.username());
-    if let (Some(username), Some(message_id))
-        = (chat.username(), message.update.forward_from_message_id())
-    {
-        let url = Url::parse(&format!("https://t.me/{}/{}", username, message_id)).ok();
-        debug!("&url is {:?}", &url);
-        url
-    } else {
-        debug!("Parse forwarded message failed");
-        debug!("chat is {:?}", chat);
-        None
-    }
-}
-
-fn get_url(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> Option<Url> {
-    let ss = ctx.update.text().to_owned()?;
-    Url::parse(ss).ok()
-}
-
-fn get_text(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> Option<String> {
-    let ss = ctx.update.text().to_owned()?;
-    Some(String::from(ss))
-}
-
-fn filter_url(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, url: Option<Url>) -> Option<Url> {
-    let url = url?;
-    // Remove params
-    // url.set_query(None);
-    let mut filtered_out = false;
-
-    let chat_id = get_chat_id(&ctx);
-    if let Some(domain) = url.domain() {
-        debug!("domain is {:?}", &domain);
-        match domain {
-            "t.me" => {
-                if let Some(mut path_segments) = url.path_segments(){
-                    match path_segments.next() {
-                        // filter out url link to messages in the current chat
-                        Some("c") => {
-                            let url_chat_id = path_segments.next();
-                            // let url_message_id = path_segments.next();
-
-                            if let Some(message_chat_id) = url_chat_id  {
-                                if message_chat_id == chat_id {
-                                    filtered_out = true;
-                                    info!("Url {} gets filtered out with chat id {}", url, message_chat_id);
-                                }
-                            }
-                        },
-                        // filter out joinchat messages
-                        Some("joinchat") => {
-                            info!("Url {} gets filtered out since it is a joinchat", url);
-                            filtered_out = true;
-                        },
-                        _ => {}
-                    }
-                }
-            },
-            "github.com" | "stackoverflow.com" => {
-                // dbg!("In github.com");
-                filtered_out = true;
-            },
-            _ => {}
-        }
-    }
-    match filtered_out {
-        true => None,
-        false => Some(url)
-    }
-}
-
-async fn get_hash_new(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, img_to_download: &PhotoSize) -> Result<Option<String>>{
-    let TgFile { file_path, .. } = ctx.requester.get_file(&img_to_download.file_id).send().await?;
-    let ss = ctx.requester.download_file_stream(&file_path);
+async fn download_file(bot: &AutoSend<Bot>, file_id: &str) -> Result<Option<Vec<u8>>>{
+    let TgFile { file_path, .. } = bot.get_file(file_id).send().await?;
+    let ss = bot.download_file_stream(&file_path);
     let l = ss.collect::<Vec<_>>().await;
-    // let mut count = 0;
     let mut buf = vec![];
     let mut found_error = false;
     for ii in l {
         match ii {
             Ok(b) => {
-                // count += 1;
                 buf.put(b);
             }
             Err(e) => {
@@ -369,364 +167,144 @@ async fn get_hash_new(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, img_to_downloa
             }
         }
     }
-    // info!("count is {:?}", count);
     if found_error {
-        warn!("Image download error! {:?}", &img_to_download);
+        warn!("Image download error! {:?}", file_id);
         Ok(None)
     } else {
-        match image::load_from_memory(&buf) {
-            Ok(img) => {
-                let hasher = img_hash::HasherConfig::new().to_hasher();
-                let hash = Some(hasher.hash_image(&img).to_base64());
-                Ok(hash)
+        Ok(Some(buf))
+    }
+}
+
+// The largest version of the photo that is at most max_width wide
+fn photo_to_download(img_vec: &[PhotoSize], max_width: u32) -> Option<&PhotoSize> {
+    let mut img_to_download: Option<&PhotoSize> = None;
+    for img in img_vec.iter() {
+        match img_to_download {
+            None => {
+                img_to_download = Some(img);
             },
-            Err(e) => {
-                warn!("Failed to parse image: {:?}", &e);
-                Ok(None)
+            Some(temp_img) => {
+                if img.width <= max_width && img.width > temp_img.width {
+                    img_to_download = Some(img);
+                }
             }
         }
     }
+    img_to_download
 }
 
-// fn sled_to_object<'a, T>(value: sled::IVec) -> T
-// where
-//     T: Deserialize<'a>
-// {
-//     serde_json::from_str::<T>(&String::from_utf8(value.to_vec()).unwrap()).unwrap()
-// }
-
-#[allow(dead_code)]
-fn object_to_sled<T>(ss: T) -> sled::IVec
-where
-    T: Serialize
-{
-    sled::IVec::from(serde_json::to_string(&ss).unwrap().as_bytes())
-}
-
-async fn insert_img_hash(img_db: &Arc<Mutex<sled::Db>>, hash: &str, chat_id: &str, key: &MessageKey) -> bool {
-    let img_db = img_db.lock().await;
-
-    let img_key = ImageKey{
-        chat_id: String::from(chat_id),
-        hash_str: String::from(hash),
-    };
-
-    let img_value = ImageValue{
-        message: key.clone(),
-        timestamp: Utc::now()
-    };
-
-    let serialized_k = serde_json::to_string(&img_key).unwrap();
-    let serialized_v = serde_json::to_string(&img_value).unwrap();
-
-    if img_db.insert(serialized_k.as_bytes(), serialized_v.as_bytes()).is_err() {
-        warn!("database seve error when saving key {:?} with value {:?}", &hash, &key);
-        false
+// Kind and file_unique_id of the media in the message, with what to download
+// for hashing: the photo, or the thumbnail of a video as its key frame
+fn media_of(msg: &Message, max_width: u32) -> Option<(MediaKind, String, Option<&PhotoSize>)> {
+    if let Some(sizes) = msg.photo() {
+        // every size of a photo has its own id, the largest comes last
+        let file_unique_id = sizes.last()?.file_unique_id.clone();
+        Some((MediaKind::Photo, file_unique_id, photo_to_download(sizes, max_width)))
+    } else if let Some(video) = msg.video() {
+        Some((MediaKind::Video, video.file_unique_id.clone(), video.thumb.as_ref()))
+    } else if let Some(animation) = msg.animation() {
+        Some((MediaKind::Animation, animation.file_unique_id.clone(), animation.thumb.as_ref()))
+    } else if let Some(video_note) = msg.video_note() {
+        Some((MediaKind::VideoNote, video_note.file_unique_id.clone(), video_note.thumb.as_ref()))
+    } else if let Some(sticker) = msg.sticker() {
+        Some((MediaKind::Sticker, sticker.file_unique_id.clone(), None))
+    } else if let Some(voice) = msg.voice() {
+        Some((MediaKind::Voice, voice.file_unique_id.clone(), None))
+    } else if let Some(audio) = msg.audio() {
+        Some((MediaKind::Audio, audio.file_unique_id.clone(), None))
     } else {
-        true
-    }
-}
-
-async fn contains_img_hash(img_db: &Arc<Mutex<sled::Db>>, hash: &str, chat_id: &str) -> bool {
-    let img_db = img_db.lock().await;
-
-    let img_key = ImageKey{
-        chat_id: String::from(chat_id),
-        hash_str: String::from(hash),
-    };
-
-    let serialized_k = serde_json::to_string(&img_key).unwrap();
-
-    match img_db.contains_key(serialized_k.as_bytes()) {
-        Err(_) => {
-            warn!("database seve error when looking for key {:?}", &img_key);
-            false
-        },
-        Ok(ans) => ans
+        msg.document().map(|document| (MediaKind::Document, document.file_unique_id.clone(), None))
     }
 }
 
-// also deletes old img_db entries
-async fn check_img_hash(img_db: &Arc<Mutex<sled::Db>>, hash: &str, chat_id: &str) -> Result<Option<MessageKey>> {
-    // images with similarity < threshold will be considered the same
-    let similarity_threshold = 4u32;
-    let hash = ImageHash::from_base64(&hash).unwrap();
-
-    // prepare an empty key so we can limit search on images from the same chat
-    let empty_key = ImageKey{chat_id: String::from(chat_id), hash_str: String::from("")};
-    let empty_key_str = serde_json::to_string(&empty_key).unwrap();
-    // the number 20 is kind of arbitrary, but seems enough to capture the first
-    // few bytes in the hash
-    let prefix = &empty_key_str.as_bytes()[0..20];
-
-    let img_db = img_db.lock().await;
-
-    let now = Utc::now();
-    let time_out_time = now.checked_sub_signed(Duration::days(TIME_OUT_DAYS)).unwrap();
-    let dry_run = false;
-
-    let mut best_hash: Option<ImageHash> = None;
-    let mut best_dist: Option<u32> = None;
-    let mut best_url: Option<MessageKey> = None;
-    let mut count = 0;
-
-    let mut old_img_set = HashSet::new();
-
-    for ans in img_db.scan_prefix(prefix) {
-        ans.ok().map(
-            |(key, value)| {
-                count += 1;
-                // let key = sled_to_object::<String>(key);
-                // let value = sled_to_object::<MessageKey>(value);
-
-                let img_key = serde_json::from_str::<ImageKey>(
-                    &String::from_utf8(key.to_vec()).unwrap()).unwrap();
-
-                let img_value = serde_json::from_str::<ImageValue>(
-                    &String::from_utf8(value.to_vec()).unwrap()).unwrap();
-
-                let value = img_value.message;
-
-                let iter_chat_id = &img_key.chat_id;
-                let iter_hash = &img_key.hash_str;
-
-                // We still need this test, as the prefix may not be perfect
-                if iter_chat_id.eq(&chat_id){
-                    // remove items too old
-                    if img_value.timestamp < time_out_time {
-                        old_img_set.insert(key.clone());
-                    } else {
-                        // info!("Saw {:?} {:?}", &key, &value);
-                        let iter_hash = ImageHash::from_base64(&iter_hash).unwrap();
-                        let dist = iter_hash.dist(&hash);
-                        match best_dist {
-                            None => {
-                                best_hash = Some(iter_hash);
-                                best_dist = Some(dist);
-                                best_url = Some(value);
-                            },
-                            Some(old_dist) => {
-                                if dist < old_dist {
-                                    best_hash = Some(iter_hash);
-                                    best_dist = Some(dist);
-                                    best_url = Some(value);
-                                }
-                            }
-                        }
-                    }
-                }
-            });
-    }
-    let match_ans = match best_dist {
-        Some(dist) => {
-            info!("The best distance is {} among all {} entries", dist, count);
-            if dist < similarity_threshold {
-                // the best match should update its timestamp, and removed from old img set
-                if let Some(best_hash) = best_hash.clone() {
-                    touch_image(&img_db, &chat_id, &best_hash, &mut old_img_set);
-                }
-                best_hash.map(|h| info!("Use this hash! {:?}", h.to_base64()));
-                best_url.as_ref().map(|u| info!("with url {:?}", u));
-                Ok(best_url)
-            } else {
-                Ok(None)
-            }
-        },
-        None => {
-            Ok(None)
-        }
-    };
-
-    // currently only try real delete on test group
-    if chat_id == String::from("-413292030") {
-        let clean_up_span = span!(Level::DEBUG, "img_clean_up");
-        let _enter = clean_up_span.enter();
-        for key in old_img_set {
-            let img_key = serde_json::from_str::<ImageKey>(
-                &String::from_utf8(key.to_vec()).unwrap()).unwrap();
-            if dry_run {
-                debug!("Dry run remove old key {:?}", &img_key)
-            } else {
-                match img_db.remove(&key){
-                    Ok(_) => {info!("Successfully removing old key {:?} from img_db", &img_key);},
-                    Err(e) => {warn!("Error in removing old key {:?} from img_db: {:?}", &img_key, &e);}
-                }
-            }
+async fn get_photo(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, img_to_download: &PhotoSize) -> Option<Vec<u8>> {
+    match download_file(&ctx.requester, &img_to_download.file_id).await {
+        Ok(buf) => buf,
+        Err(e) => {
+            warn!("Get photo error {:?}", e);
+            None
         }
     }
-    match_ans
 }
 
-fn touch_image(img_db: &MutexGuard<sled::Db>, chat_id: &str, hash_str: &ImageHash,
-               old_img_set: &mut HashSet<sled::IVec>) -> bool {
-    let best_key = ImageKey{
-        chat_id: String::from(chat_id),
-        hash_str: String::from(hash_str.to_base64())
-    };
-    let serialized_key = serde_json::to_string(&best_key).unwrap();
-
-    old_img_set.remove(serialized_key.as_bytes());
-
-    let value = img_db.get(serialized_key.as_bytes());
-    if let Ok(Some(value)) = value {
-        let mut v = serde_json::from_str::<ImageValue>(&String::from_utf8(value.to_vec()).unwrap()).unwrap();
-        v.timestamp = Utc::now();
-        let serized_v = serde_json::to_string(&v).unwrap();
-        match img_db.insert(serialized_key.as_bytes(), serized_v.as_bytes()) {
-            Ok(_) => {info!("Timestamp updated for {:?}", &best_key.hash_str)},
-            Err(_) => {warn!("Timestamp update failed for {:?}", &best_key.hash_str)}
-        };
-    }
-    true
+// Part of `text` covered by an entity, whose offset and length count UTF-16 code units
+fn entity_text(text: &str, offset: usize, length: usize) -> Option<String> {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    units.get(offset..offset + length).map(String::from_utf16_lossy)
 }
 
-
-async fn reset_top_board(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
-                         top_db: &Arc<Mutex<sled::Db>>){
-
-    let chat_id = get_chat_id(&ctx);
-
-    // prepare an empty key so we can limit search on images from the same chat
-    let empty_key = UserKey{chat_id: chat_id.clone(), user_id: 0};
-    let empty_key_str = serde_json::to_string(&empty_key).unwrap();
-    // the number 20 is kind of arbitrary, but seems enough to capture the first
-    // few bytes in the hash
-    let prefix = &empty_key_str.as_bytes()[0..20];
-
-    let top_db = top_db.lock().await;
-
-    let mut count = 0;
-    for ans in top_db.scan_prefix(prefix) {
-        ans.ok().map(
-            |(key, value)| {
-                count += 1;
-                // let key = sled_to_object::<String>(key);
-                // let value = sled_to_object::<MessageKey>(value);
-
-                let iter_key = serde_json::from_str::<UserKey>(
-                    &String::from_utf8(key.to_vec()).unwrap()).unwrap();
-
-                let iter_value = serde_json::from_str::<TopUserValue>(
-                    &String::from_utf8(value.to_vec()).unwrap()).unwrap();
-
-                let iter_chat_id = &iter_key.chat_id;
-                let iter_user_id = &iter_key.user_id;
-                let username = match iter_value.username.clone() {
-                    Some(user_name) => user_name,
-                    None => iter_user_id.to_string()
-                };
-
-                // We still need this test, as the prefix may not be perfect
-                if iter_chat_id.eq(&chat_id){
-                    let key = UserKey{
-                        chat_id: chat_id.clone(),
-                        user_id: iter_user_id.clone(),
-                    };
-                    let value = TopUserValue{
-                        username: Some(username.clone()),
-                        count: 0
-                    };
-                    let key = serde_json::to_string(&key).unwrap();
-                    let value = serde_json::to_string(&value).unwrap();
-
-                    if let Err(e) = top_db.insert(key.as_bytes(), value.as_bytes()) {
-                        warn!("top database error {:?} when saving key {:?} with value {:?}", &e, &key, &value);
-                    }
-                }
-            });
-    }
-
-    let final_msg = String::from("本群火星排行榜已重置");
-    if let Ok(_answer_status) = ctx.reply_to(final_msg).await {
-        // dbg!(answer_status);
+// Links in the text or caption, both written out and hidden behind text links
+fn message_links(msg: &Message) -> Vec<String> {
+    let with_text = [(msg.text(), msg.entities()), (msg.caption(), msg.caption_entities())];
+    let mut links = vec![];
+    for (text, entities) in with_text.iter() {
+        let (text, entities) = match (text, entities) {
+            (Some(text), Some(entities)) => (text, entities),
+            _ => continue,
+        };
+        for entity in entities.iter() {
+            match &entity.kind {
+                MessageEntityKind::Url => links.extend(entity_text(text, entity.offset, entity.length)),
+                MessageEntityKind::TextLink { url } => links.push(url.clone()),
+                _ => {}
+            }
+        }
     }
+    links
 }
 
-async fn update_top_board(top_db: &Arc<Mutex<sled::Db>>, chat_id: &str, user_id: &Option<i64>, username: &Option<String>){
-
-    if let Some(user_id) = user_id {
-
-        let key = UserKey{
-            chat_id: String::from(chat_id),
-            user_id: user_id.clone(),
-        };
-        let key = serde_json::to_string(&key).unwrap();
-
-        let top_db = top_db.lock().await;
-        match top_db.get(key.as_bytes()) {
-            Err(e) => {
-                warn!("top board database get error {:?} when looking for key {:?}", &e, &key);
-            },
-            Ok(value) => {
-                let mut previous_value: i64 = 0;
-                if let Some(value) = value {
-                    let value = String::from_utf8(value.to_vec()).unwrap();
-                    info!("In top db, finding '{:?}' returns '{}'", &key, &value);
-                    let previous_user_value = serde_json::from_str::<TopUserValue>(&value).unwrap();
-                    previous_value = previous_user_value.count;
-                }
-
-                let value = TopUserValue{
-                    username: username.clone(),
-                    count: previous_value + 1
-                };
-                let value = serde_json::to_string(&value).unwrap();
-
-                if let Err(e) = top_db.insert(key.as_bytes(), value.as_bytes()) {
-                    warn!("database seve error {:?} when saving key {:?} with value {:?}", &e, &key, &value);
-                }
-            }
+// Converts a Telegram update into what the detector understands
+async fn to_incoming(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, detector: &DuplicateDetector,
+                     settings: &ChatSettings) -> IncomingMessage {
+    let msg = &ctx.update;
+    let sender = msg.from().map(|u| Sender {
+        id: u.id,
+        name: match u.last_name.clone() {
+            Some(last_name) => format!("{} {}", u.first_name, last_name),
+            None => u.first_name.clone()
         }
+    });
+    let forward = if let Some(chat) = msg.forward_from_chat() {
+        debug!("chat.username() is {:?}", chat.username());
+        Some(ForwardOrigin::Chat {
+            username: chat.username().map(String::from),
+            message_id: msg.forward_from_message_id().copied(),
+        })
+    } else {
+        msg.forward_from().map(|_| ForwardOrigin::User)
+    };
+    let media = media_of(msg, settings.max_photo_width);
+    let to_download = media.as_ref().and_then(|(_, _, size)| *size);
+    let photo = match (&media, to_download) {
+        // an exact re-send is recognized without downloading anything
+        (Some((_, id, _)), _) if detector.knows_file(&get_chat_id(ctx), id).await => None,
+        (Some(_), Some(size)) => get_photo(ctx, size).await,
+        _ => None,
+    };
+    IncomingMessage {
+        chat_id: msg.chat_id(),
+        chat_username: msg.chat.username().map(String::from),
+        is_private: msg.chat.is_private(),
+        message_id: msg.id,
+        sender,
+        text: msg.text().or_else(|| msg.caption()).map(String::from),
+        links: message_links(msg),
+        forward,
+        media: media.as_ref().map(|(kind, _, _)| *kind),
+        file_unique_id: media.as_ref().map(|(_, id, _)| id.clone()),
+        photo_file_id: to_download.map(|size| size.file_id.clone()),
+        photo,
     }
 }
 
 async fn print_topics(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
-                      db: Arc<Mutex<MyDB>>, chat_id: &str) {
-    // prepare an empty key so we can limit search on images from the same chat
-    let empty_key = MessageKey{
-        chat_id: String::from(chat_id),
-        // dummy url to make it happy
-        url:Url::parse("https://example.net").unwrap()};
-    let empty_key_str = serde_json::to_string(&empty_key).unwrap();
-    // the number 20 is kind of arbitrary, but seems enough to capture the first
-    // few bytes in the hash
-    let prefix = &empty_key_str.as_bytes()[0..20];
-
-    let db = db.lock().await;
-
-    let mut count = 0;
-    let mut heap = BinaryHeap::new();
-    for ans in db.scan_prefix(prefix) {
-        ans.ok().map(
-            |(key, value)| {
-                count += 1;
-                // let key = sled_to_object::<String>(key);
-                // let value = sled_to_object::<MessageKey>(value);
-
-                let top_key = serde_json::from_str::<MessageKey>(
-                    &String::from_utf8(key.to_vec()).unwrap()).unwrap();
-
-                let value = serde_json::from_str::<MessageInfo>(
-                    &String::from_utf8(value.to_vec()).unwrap()).unwrap();
-
-                let iter_chat_id = &top_key.chat_id;
-                let link = match value.link.clone() {
-                    Some(url) => url.to_string(),
-                    None => String::from("Link not available")
-                };
-
-                // We still need this test, as the prefix may not be perfect
-                if iter_chat_id.eq(&chat_id){
-                    heap.push((value.count, link));
-                }
-            });
-    }
+                      detector: &DuplicateDetector, chat_id: &str) {
+    let topics = detector.topics(chat_id).await;
     let mut final_msg = String::from("火星话题排行榜：\n\n");
-    let mut count = 1;
     let max_len = 20;
     let last_len_hard = 30;
     let mut last_count = 0;
-    while let Some((value, link)) = heap.pop() {
+    for (count, (value, link)) in (1..).zip(topics) {
         if count > max_len && (count > last_len_hard || value < last_count) {
             break;
         }
@@ -735,9 +313,8 @@ async fn print_topics(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
                                    format!("{}.", &count),
                                    format!("火星{}次：", &value),
                                    &link).as_str());
-        count += 1;
     }
-    let chat_id = ctx.chat_id().clone();
+    let chat_id = ctx.chat_id();
     if let Ok(_answer_status) = ctx.requester.inner().send_message(chat_id, final_msg)
                                                      // .disable_web_page_preview(true)
                                                      .send().await {
@@ -745,155 +322,330 @@ async fn print_topics(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
 }
 
 async fn print_top_board(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
-                         top_db: &Arc<Mutex<sled::Db>>, chat_id: &str) {
-    // prepare an empty key so we can limit search on images from the same chat
-    let empty_key = UserKey{chat_id: String::from(chat_id), user_id: 0};
-    let empty_key_str = serde_json::to_string(&empty_key).unwrap();
-    // the number 20 is kind of arbitrary, but seems enough to capture the first
-    // few bytes in the hash
-    let prefix = &empty_key_str.as_bytes()[0..20];
-
-    let top_db = top_db.lock().await;
-
-    let mut count = 0;
-    let mut heap = BinaryHeap::new();
-    for ans in top_db.scan_prefix(prefix) {
-        ans.ok().map(
-            |(key, value)| {
-                count += 1;
-                // let key = sled_to_object::<String>(key);
-                // let value = sled_to_object::<MessageKey>(value);
-
-                let top_key = serde_json::from_str::<UserKey>(
-                    &String::from_utf8(key.to_vec()).unwrap()).unwrap();
-
-                let value = serde_json::from_str::<TopUserValue>(
-                    &String::from_utf8(value.to_vec()).unwrap()).unwrap();
-
-                let iter_chat_id = &top_key.chat_id;
-                let username = match value.username.clone() {
-                    Some(user_name) => user_name,
-                    None => top_key.user_id.to_string()
-                };
-
-                // We still need this test, as the prefix may not be perfect
-                if iter_chat_id.eq(&chat_id) && value.count > 0{
-                    heap.push((value.count, username));
-                }
-            });
-    }
+                         detector: &DuplicateDetector, chat_id: &str) {
+    let board = detector.top_board(chat_id).await;
     let mut final_msg = String::from("火星排行榜：\n\n");
-    let mut count = 1;
     let max_len = 20;
     let last_len_hard = 30;
     let mut last_count = 0;
-    if heap.len() > 0 {
-        while let Some((value, username)) = heap.pop() {
+    if !board.is_empty() {
+        for (count, (value, username)) in (1..).zip(board) {
             if count > max_len && (count > last_len_hard || value < last_count) {
                 break;
             }
             last_count = value;
             final_msg.push_str(format!("{}. {} 火星了{}次\n", &count, &username, &value).as_str());
-            count += 1;
         }
     } else {
         final_msg.push_str("本群还没有人火星过！\n");
     }
-    let chat_id = ctx.chat_id().clone();
+    let chat_id = ctx.chat_id();
     if let Ok(_answer_status) = ctx.requester.inner().send_message(chat_id, final_msg)
                                                      .disable_web_page_preview(true)
                                                      .send().await {
-
-        // dbg!(answer_status);
     }
-    // if let Ok(_answer_status) = ctx.answer(final_msg).await {
-    //     // dbg!(answer_status);
-    // }
 }
 
 async fn print_my_number(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
-                         top_db: &Arc<Mutex<sled::Db>>) {
-    let chat_id = get_chat_id(&ctx);
-    let user_id = ctx.update.from().map_or(None, |u| Some(u.id));
+                         detector: &DuplicateDetector) {
+    let chat_id = get_chat_id(ctx);
+    let user_id = ctx.update.from().map(|u| u.id);
+
+    let final_msg = match user_id {
+        Some(user_id) => match detector.user_count(&chat_id, user_id).await {
+            Some(count) if count > 0 => format!("您已经火星{}次了！", count),
+            _ => String::from("恭喜您，您还没有火星过！")
+        },
+        None => String::from("找不到您的user_id\n")
+    };
 
-    let mut final_msg = String::from("");
+    if let Ok(_answer_status) = ctx.reply_to(final_msg).await {
+    }
+}
 
-    if let Some(user_id) = user_id {
+async fn reset_top_board(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
+                         detector: &DuplicateDetector) {
+    let chat_id = get_chat_id(ctx);
+    detector.reset_top_board(&chat_id).await;
 
-        let top_db = top_db.lock().await;
-        let key = UserKey{
-            chat_id: String::from(chat_id),
-            user_id: user_id.clone(),
-        };
-        let key = serde_json::to_string(&key).unwrap();
+    let final_msg = String::from("本群火星排行榜已重置");
+    if let Ok(_answer_status) = ctx.reply_to(final_msg).await {
+    }
+}
 
-        match top_db.get(key.as_bytes()) {
-            Err(e) => {
-                warn!("top board database get error {:?} when looking for key {:?}", &e, &key);
-            },
-            Ok(Some(value)) => {
-                    let value = String::from_utf8(value.to_vec()).unwrap();
-                    info!("In top db, finding '{:?}' returns '{}'", &key, &value);
-                    let value = serde_json::from_str::<TopUserValue>(&value).unwrap();
-                    if value.count > 0 {
-                        final_msg.push_str(format!("您已经火星{}次了！", value.count).as_str());
-                    } else {
-                        final_msg.push_str(format!("恭喜您，您还没有火星过！").as_str());
-                    }
+// Arguments of a command, without the mention of the bot
+fn command_args(args: &str) -> Vec<&str> {
+    let mention = format!("@{}", bot_name());
+    args.split_whitespace()
+        .filter(|w| *w != mention)
+        .collect()
+}
+
+// The value of `/config set <key> <value>` in the raw message text: whatever
+// follows the key, so that newlines and runs of spaces in a template survive.
+// Only the separator after the key and a trailing mention are left out.
+fn setting_value<'a>(text: &'a str, mention: &str) -> &'a str {
+    let mut rest = text;
+    // the command, `set` and the key, a mention of the bot is not a word
+    let mut words = 0;
+    while words < 3 && !rest.is_empty() {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        if rest[..end] != *mention {
+            words += 1;
+        }
+        rest = &rest[end..];
+    }
+    let mut chars = rest.chars();
+    chars.next();
+    let value = chars.as_str();
+    match value.trim_end().strip_suffix(mention) {
+        Some(value) => value.trim_end(),
+        None => value,
+    }
+}
+
+// Adds a rule with the given action, or removes the rule if there is no action
+async fn edit_rules(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, detector: &DuplicateDetector,
+                    action: Option<RuleAction>, args: &str) -> Result<(), RequestError> {
+    let chat_id = get_chat_id(ctx);
+    let final_msg = match command_args(args).as_slice() {
+        ["-g", pattern] | [pattern, "-g"] => edit_rule(detector, GLOBAL_RULES, action, pattern).await,
+        [pattern] => edit_rule(detector, &chat_id, action, pattern).await,
+        _ => String::from("用法：/<command> <域名或模式> [-g]，例如 /ignore github.com 或 /ignore t.me/joinchat/*")
+    };
+    ctx.reply_to(final_msg).send().await?;
+    Ok(())
+}
+
+async fn edit_rule(detector: &DuplicateDetector, key: &str, action: Option<RuleAction>, pattern: &str) -> String {
+    let scope = if key == GLOBAL_RULES { "所有群" } else { "本群" };
+    match action {
+        Some(action) => {
+            let rule = Rule::new(pattern, action);
+            let msg = format!("{}已添加规则：{}", scope, &rule);
+            detector.add_rule(key, rule).await;
+            msg
+        },
+        None => {
+            if detector.remove_rule(key, pattern).await {
+                format!("{}已删除 {} 的规则", scope, pattern)
+            } else {
+                format!("{}没有 {} 的规则", scope, pattern)
+            }
+        }
+    }
+}
+
+async fn print_rules(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
+                     detector: &DuplicateDetector) -> Result<(), RequestError> {
+    let rules = detector.rules(&get_chat_id(ctx)).await;
+    let mut final_msg = String::from("本群规则：\n");
+    if rules.chat.is_empty() {
+        final_msg.push_str("（无）\n");
+    }
+    for rule in rules.chat.iter() {
+        final_msg.push_str(&format!("{}\n", rule));
+    }
+    final_msg.push_str("\n所有群的规则：\n");
+    if rules.global.is_empty() {
+        final_msg.push_str("（无）\n");
+    }
+    for rule in rules.global.iter() {
+        final_msg.push_str(&format!("{}\n", rule));
+    }
+    ctx.requester.inner().send_message(ctx.chat_id(), final_msg)
+                         .disable_web_page_preview(true)
+                         .send().await?;
+    Ok(())
+}
+
+async fn configure(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
+                   detector: &DuplicateDetector, args: &str) -> Result<(), RequestError> {
+    let chat_id = get_chat_id(ctx);
+    let args = command_args(args);
+    let mut settings = detector.settings(&chat_id).await;
+    let final_msg = match args.as_slice() {
+        [] | ["get"] => format!("本群设置：\n{}", settings),
+        ["get", key] => match settings.get(key) {
+            Some(value) => format!("{} = {}", key, value),
+            None => format!("没有这个设置：{}，可用的设置有：{}", key, ChatSettings::KEYS.join(", "))
+        },
+        ["set", key, value @ ..] => {
+            let value = match ctx.update.text() {
+                Some(text) => String::from(setting_value(text, &format!("@{}", bot_name()))),
+                None => value.join(" "),
+            };
+            match settings.set(key, &value) {
+                Ok(()) => {
+                    detector.save_settings(&chat_id, &settings).await;
+                    format!("已设置 {} = {}", key, settings.get(key).unwrap_or_default())
+                },
+                Err(e) => format!("设置失败：{}", e)
+            }
+        },
+        ["reset"] => {
+            detector.reset_settings(&chat_id).await;
+            String::from("本群设置已恢复默认")
+        },
+        _ => String::from("用法：/config get [key]，/config set <key> <value>，/config reset")
+    };
+    ctx.reply_to(final_msg).send().await?;
+    Ok(())
+}
+
+// A single reply for everything in the message that was seen before, each
+// with an earlier occurrence that still exists
+fn duplicate_msg(settings: &ChatSettings, found: &[(Duplicate, Occurrence)]) -> String {
+    let now = chrono::Utc::now();
+    found.iter()
+         .map(|(duplicate, original)| template::render(settings.template(duplicate.kind), duplicate, original, now))
+         .collect::<Vec<String>>()
+         .join("\n\n")
+}
+
+// Each probe costs a forward and a delete, so only this many are made for a
+// duplicate, this far apart, to stay clear of Telegram's rate limits
+static MAX_PROBES: usize = 5;
+static PROBE_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_millis(300);
+
+// The newest earlier occurrence that still exists, see 存在性原则 in the PRD.
+// Deleted ones are forgotten on the way. Originals that could not be probed
+// count as existing, so that an outage never loses an occurrence. Originals
+// are looked up with `probe`, which is given their message id.
+async fn surviving_original<P, F>(detector: &DuplicateDetector, chat_id: &str, duplicate: &Duplicate,
+                                  probe: P) -> Option<Occurrence>
+where
+    P: Fn(i32) -> F,
+    F: Future<Output = Existence>,
+{
+    // every original before this one was probed and found deleted
+    for (probes, original) in duplicate.originals.iter().rev().enumerate() {
+        let id = match original.message_id {
+            Some(id) => id,
+            // recorded before message ids were kept, there is nothing to probe
+            None => return Some(original.clone()),
+        };
+        if probes == MAX_PROBES {
+            // the older ones are left for the next duplicate
+            info!("Gave up on {} after {} deleted originals", &duplicate.url, probes);
+            return None;
+        }
+        if probes > 0 {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+        }
+        match probe(id).await {
+            Existence::Exists => return Some(original.clone()),
+            Existence::Unknown => {
+                warn!("Could not tell whether original {} of {} exists, assuming it does", id, &duplicate.url);
+                return Some(original.clone());
             },
-            Ok(None) => {
-                final_msg.push_str(format!("恭喜您，您还没有火星过！").as_str());
+            Existence::Deleted => {
+                info!("Original {} of {} was deleted", id, &duplicate.url);
+                detector.forget_occurrence(chat_id, &duplicate.url, id).await;
             }
         }
-    } else {
-        final_msg.push_str(format!("找不到您的user_id\n").as_str())
     }
+    None
+}
 
-    if let Ok(_answer_status) = ctx.reply_to(final_msg).await {
-        // dbg!(answer_status);
+// Messages of albums that are still arriving, by media_group_id
+static ALBUMS: Lazy<Albums<Message>> = Lazy::new(|| Albums { albums: Default::default() });
+// How long to wait for the rest of an album, whose messages arrive one by one
+static ALBUM_WAIT: tokio::time::Duration = tokio::time::Duration::from_millis(1500);
+
+// Messages of the albums whose rest may still be on its way, by media group id
+struct Albums<T> {
+    albums: std::sync::Mutex<HashMap<String, Vec<T>>>,
+}
+
+impl<T> Albums<T> {
+    // Adds a message to its album, returns whether it is the first one
+    fn push(&self, media_group_id: &str, msg: T) -> bool {
+        let mut albums = self.albums.lock().unwrap();
+        let album = albums.entry(String::from(media_group_id)).or_default();
+        album.push(msg);
+        album.len() == 1
     }
 
+    // Every message of the album that arrived so far, in the order they did
+    fn take(&self, media_group_id: &str) -> Vec<T> {
+        self.albums.lock().unwrap().remove(media_group_id).unwrap_or_default()
+    }
 }
 
-#[allow(dead_code)]
-async fn cleanup_img_db(img_db: &Arc<Mutex<sled::Db>>, chat_id: &str) -> Result<()> {
-    let img_db = img_db.lock().await;
-    let mut count = 0;
-    let now = Utc::now();
-    if let Some(time_out_time) = now.checked_sub_signed(Duration::days(TIME_OUT_DAYS)) {
-        for ans in img_db.iter() {
-            ans.ok().map(
-                |(key, value)| {
-                    count += 1;
-                    // let key = sled_to_object::<String>(key);
-                    // let value = sled_to_object::<MessageKey>(value);
-
-                    let img_key = serde_json::from_str::<ImageKey>(
-                        &String::from_utf8(key.to_vec()).unwrap()).unwrap();
-
-                    let img_value = serde_json::from_str::<ImageValue>(
-                        &String::from_utf8(value.to_vec()).unwrap()).unwrap();
-
-                    let iter_chat_id = &img_key.chat_id;
-                    // let iter_hash = &img_key.hash_str;
-
-                    if iter_chat_id.eq(&chat_id){
-                        // remove items too old
-                        if img_value.timestamp < time_out_time {
-                            match img_db.remove(&key){
-                                Ok(_) => {
-                                    info!("Successfully removing {:?} from img_db", &img_key);
-                                },
-                                Err(e) => {
-                                    warn!("Error in removing {:?} from img_db, error {:?}", &img_key, &e);
-                                }
-                            }
-                        }
-                    }
-                });
+// Keeps the message until the rest of its album arrived, then checks the album as a whole
+fn buffer_album(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, detector: Arc<DuplicateDetector>,
+                media_group_id: &str) {
+    if !ALBUMS.push(media_group_id, ctx.update.clone()) {
+        return;
+    }
+    let bot = ctx.requester.clone();
+    let media_group_id = String::from(media_group_id);
+    tokio::spawn(async move {
+        tokio::time::sleep(ALBUM_WAIT).await;
+        let mut msgs = ALBUMS.take(&media_group_id);
+        msgs.sort_by_key(|msg| msg.id);
+        info!("Album {} has {} messages", &media_group_id, msgs.len());
+        let ctxs: Vec<_> = msgs.into_iter()
+                               .map(|update| UpdateWithCx { requester: bot.clone(), update })
+                               .collect();
+        if let Err(e) = parse_album(&ctxs, detector).await {
+            warn!("parse_album see error {:?}", e)
         }
+    });
+}
+
+// A single reply for the photos of an album that were seen before, each
+// rendered with the template of its kind
+fn album_msg(settings: &ChatSettings, total: usize, found: &[(usize, Duplicate, Occurrence)]) -> String {
+    let now = chrono::Utc::now();
+    let seen: BTreeSet<usize> = found.iter().map(|(i, _, _)| *i).collect();
+    let mut final_msg = if seen.len() == total {
+        format!("这个相册的{}个全部来过本群了：", total)
     } else {
-        warn!("Time parse failuer when cleaning up db!");
+        let seen: Vec<String> = seen.iter().map(|i| i.to_string()).collect();
+        format!("这个相册的第{}个来过本群了：", seen.join("、"))
+    };
+    for (i, duplicate, original) in found {
+        let reply = template::render(settings.template(duplicate.kind), duplicate, original, now);
+        final_msg.push_str(&format!("\n\n第{}个：{}", i, reply));
+    }
+    final_msg
+}
+
+async fn parse_album(ctxs: &[UpdateWithCx<AutoSend<Bot>, Message>], detector: Arc<DuplicateDetector>) -> Result<()> {
+    let first = match ctxs.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let settings = detector.settings(&get_chat_id(first)).await;
+    let mut incoming = vec![];
+    for ctx in ctxs {
+        incoming.push(to_incoming(ctx, &detector, &settings).await);
+    }
+
+    let (chat_id, raw_chat_id) = (get_chat_id(first), first.update.chat_id());
+    let mut found = vec![];
+    for (i, duplicates) in (1..).zip(detector.check_album(&incoming).await?) {
+        for duplicate in duplicates {
+            match surviving_original(&detector, &chat_id, &duplicate, |id| probe(first, raw_chat_id, id)).await {
+                Some(original) => found.push((i, duplicate, original)),
+                None => info!("No original of {} is left, not a duplicate", &duplicate.url),
+            }
+        }
+    }
+    if found.is_empty() {
+        return Ok(());
+    }
+    detector.count_duplicate(&incoming[0], found.iter().map(|(_, duplicate, _)| duplicate)).await;
+    let final_msg = album_msg(&settings, ctxs.len(), &found);
+    info!("{}", &final_msg);
+    // delete my message if the album gets deleted
+    if let Ok(msg) = first.reply_to(final_msg).await {
+        let raw_chat_id = first.update.chat_id();
+        let wait = tokio::time::Duration::from_secs(settings.delete_check_secs);
+        delete_final_msg_accordingly(first, raw_chat_id, first.update.id, msg.id, wait).await;
     }
     Ok(())
 }
@@ -901,172 +653,82 @@ async fn cleanup_img_db(img_db: &Arc<Mutex<sled::Db>>, chat_id: &str) -> Result<
 async fn
 parse_message(
     ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
-    db: Arc<Mutex<MyDB>>,
-    img_db: Arc<Mutex<sled::Db>>,
-    top_db: Arc<Mutex<sled::Db>>
+    detector: Arc<DuplicateDetector>,
 ) -> Result<()> {
-    let mut url: Option<Url>;
-    let link = get_msg_link(&ctx);
-    let clean_chat_id = get_chat_id(&ctx);
-    let user_id = ctx.update.from().map_or(None, |u| Some(u.id));
-    let username = ctx.update.from().map_or(None,
-                                            |u|
-                                            match u.last_name.clone() {
-                                                Some(last_name) => Some(format!("{} {}", u.first_name.clone(), last_name)),
-                                                None => Some(u.first_name.clone())
-                                            });
     let msg_id = ctx.update.id;
+    let settings = detector.settings(&get_chat_id(ctx)).await;
+    let incoming = to_incoming(ctx, &detector, &settings).await;
 
-    match (is_forward(&ctx), is_image(&ctx)) {
-        (true, false) => {
-            // is a forward message
-            url = get_forward_msg_link(&ctx);
-            if url.is_some(){
-                trace!("Found a forwarded channel message");
-            } else {
-                trace!("Forwarded message link parse failure.")
-            }
-        },
-        // (true, true) => {
-        //     // is a forward and an image
-        //     info!("Found an image message that is also forward");
-        //     url = get_forward_msg_link(&ctx);
-        //     if url.is_none(){
-        //         info!("Forwarded message link parse failure.")
-        //     }
-        // },
-        (_, true) => {
-            // is an image, but not forward
-            trace!("Found an image message that is not a channel forward");
-            url = None;
-            let img_vec = ctx.update.photo()
-                                    .ok_or(Error::new(ErrorKind::Other, "failed to download img_vec"))?;
-            let mut img_to_download: Option<PhotoSize> = None;
-            for img in img_vec.iter() {
-                // dbg!(img);
-                match img_to_download {
-                    None => {
-                        img_to_download = Some(img.clone());
-                    },
-                    Some(ref temp_img) => {
-                        if img.width <= 600 && img.width > temp_img.width {
-                            img_to_download = Some(img.clone());
-                        }
-                    }
-                }
-            }
-            if let Some(img) = img_to_download {
-                match get_hash_new(&ctx, &img).await {
-                    Ok(Some(hash)) => {
-                        trace!("Get hash {}", &hash);
-                        match check_img_hash(&img_db, &hash, &clean_chat_id).await {
-                            Ok(Some(key)) => {
-                                info!("Found existing hash {:?} that is close", key.url);
-                                url = Some(key.url.clone());
-                            },
-                            _ => {
-                                trace!("No close hash is found, use original hash {:?}", &hash);
-                                url = Url::parse(&format!("https://img.telegram.com/{}", hash.clone())).ok();
-                            }
-                        }
-                        // insert the new hash result into img_db, unless an exact key exist.
-                        if let Some(url) = url.clone() {
-                            if !contains_img_hash(&img_db, &hash, &clean_chat_id).await {
-                                let key = MessageKey{chat_id: clean_chat_id.clone(), url:url.clone()};
-                                let ans = insert_img_hash(&img_db, &hash, &clean_chat_id, &key).await;
-                                if ! ans {
-                                    warn!("insert error, with hash {:?} and key {:?}", &hash, &key);
-                                }
-                            }
-                        }
-                    },
-                    Ok(None) => {
-                        warn!("Failed to get hash");
-                    }
-                    Err(e) => {
-                        warn!("Get hash error {:?}", e);
-                    }
-                };
-            }
-        },
-        (false, false) => {
-            // not forward nor image, only interested in pure url
-            // info!("Found a non-forward, non-image message");
-            url = get_url(&ctx);
-            if url.is_none(){
-                debug!("Non-forwarded message link parse failure.")
+    let mut my_msg_id: Option<i32> = None;
+    if let Verdict::Duplicate(duplicates) = detector.check(&incoming).await? {
+        let (chat_id, raw_chat_id) = (get_chat_id(ctx), ctx.update.chat_id());
+        let mut found = vec![];
+        for duplicate in duplicates {
+            match surviving_original(&detector, &chat_id, &duplicate, |id| probe(ctx, raw_chat_id, id)).await {
+                Some(original) => found.push((duplicate, original)),
+                None => info!("No original of {} is left, not a duplicate", &duplicate.url),
             }
-            url = filter_url(&ctx, url);
         }
-    }
-    let mut my_msg_id: Option<i32> = None;
-    if let Some(url) = url {
-        let key = MessageKey{chat_id: clean_chat_id.clone(), url:url.clone()};
-        let db = db.lock().await;
-        if let Some(info) = db.find(&key){
-            let mut info = info.clone();
-            // has seen this message before
-            info.count += 1;
-            db.save(&key, &info);
-            update_top_board(&top_db, &clean_chat_id, &user_id, &username).await;
-            // ctx.answer(format!("See it {} times", info.count)).await?;
-            info!("See it {} times", info.count);
-            let link_msg = &info.link.map_or(
-                format!("第一次出现是在private chat"),
-                |url| format!("第一次出现是在：{}", url));
-            // ctx.answer(&link_msg).await?;
-            let final_msg = format!("你火星了！这条消息是第{}次来到本群了，快去爬楼。{}", info.count, link_msg);
+        if !found.is_empty() {
+            detector.count_duplicate(&incoming, found.iter().map(|(duplicate, _)| duplicate)).await;
+            let final_msg = duplicate_msg(&settings, &found);
             info!("{}", &final_msg);
             if let Ok(msg) = ctx.reply_to(final_msg).await {
                 my_msg_id = Some(msg.id);
             }
-        } else {
-            // has not seen this message before
-            let value = MessageInfo{url, count:1, link, user_id};
-            db.save(&key, &value);
-        };
-    } else {
-        get_text(&ctx).map(|text| debug!("Msg: {}", text));
+        }
     }
     // delete my message if the message we replied to gets deleted
     if let Some(my_msg_id) = my_msg_id {
         let raw_chat_id = ctx.update.chat_id();
-        delete_final_msg_accordingly(&ctx, raw_chat_id, msg_id, my_msg_id).await;
+        let wait = tokio::time::Duration::from_secs(settings.delete_check_secs);
+        delete_final_msg_accordingly(ctx, raw_chat_id, msg_id, my_msg_id, wait).await;
     }
     Ok(())
 }
 
-async fn delete_final_msg_accordingly(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, chat_id: i64, msg_id: i32, my_msg_id: i32) -> bool{
-    let check_wait_duration = tokio::time::Duration::from_secs(30);
-    tokio::time::sleep(check_wait_duration).await;
+// What probing a message found out about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Existence {
+    Exists,
+    Deleted,
+    // the probe failed for another reason, e.g. a network error or a rate limit
+    Unknown,
+}
 
+// Whether a message is still in the chat, found out by forwarding it to the
+// same chat and deleting the forward again
+async fn probe(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, chat_id: i64, msg_id: i32) -> Existence {
     match ctx.requester.forward_message(chat_id, chat_id, msg_id).await {
         Ok(msg) => {
-            // // message was not deleted
-            // info!("Get msg {:?}", &msg);
-            // info!("Message was not deleted, delete the new forward");
+            // message was not deleted, delete the new forward
             if let Err(e) = ctx.requester.delete_message(chat_id, msg.id).await {
                 warn!("Delete failed with error {:?}", e);
             };
+            Existence::Exists
+        },
+        Err(RequestError::ApiError { kind: ApiError::MessageToForwardNotFound, .. })
+        | Err(RequestError::ApiError { kind: ApiError::MessageIdInvalid, .. }) => {
+            info!("Message {} in chat {} was deleted", msg_id, chat_id);
+            Existence::Deleted
         },
         Err(e) => {
-            // unknown error
-            info!("The attempt to forward the message failed");
-             match e {
-                 RequestError::ApiError{kind, status_code:_}
-                 if kind == ApiError::MessageToForwardNotFound ||
-                     kind == ApiError::MessageIdInvalid => {
-                    info!("The message was deleted, so we also delete our notification");
-                },
-                _ => {
-                    warn!("Some other error detected: {:?}", e);
-                }
-            }
-            if let Err(e) =  ctx.requester.delete_message(chat_id, my_msg_id).await {
-                warn!("Clean up chat {} message {} failed with error {:?}", chat_id, my_msg_id, e);
-            };
+            warn!("The attempt to forward message {} in chat {} failed with error {:?}", msg_id, chat_id, e);
+            Existence::Unknown
         }
     }
+}
+
+async fn delete_final_msg_accordingly(ctx: &UpdateWithCx<AutoSend<Bot>, Message>, chat_id: i64, msg_id: i32, my_msg_id: i32,
+                                      check_wait_duration: tokio::time::Duration) -> bool{
+    tokio::time::sleep(check_wait_duration).await;
+
+    if probe(ctx, chat_id, msg_id).await == Existence::Deleted {
+        info!("The message was deleted, so we also delete our notification");
+        if let Err(e) =  ctx.requester.delete_message(chat_id, my_msg_id).await {
+            warn!("Clean up chat {} message {} failed with error {:?}", chat_id, my_msg_id, e);
+        };
+    }
     true
 }
 
@@ -1075,58 +737,38 @@ fn is_forward(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
     ctx.update.forward_from().is_some() || ctx.update.forward_from_chat().is_some()
 }
 
-fn is_image(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
-    ctx.update.photo().is_some()
-    // match ctx.update.photo() {
-    //     Some(img) => {
-    //         // dbg!(img);
-    //         true
-    //     },
-    //     None => false
-    // }
+fn is_media(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
+    media_of(&ctx.update, 0).is_some()
 }
 
 fn need_handle(ctx: &UpdateWithCx<AutoSend<Bot>, Message>) -> bool {
-    // dbg!(ctx.update.chat.is_private());
-    // dbg!(ctx.update.chat_id());
-    // dbg!(ctx.update.id);
-    // dbg!(ctx.update.forward_from());
-    // dbg!(ctx.update.forward_from_chat());
-    // dbg!(ctx.update.forward_from_message_id());
-    // dbg!(ctx.update.forward_date());
-    // dbg!(ctx.update.forward_signature());
-    // dbg!(ctx.update.reply_to_message());
-
-    is_forward(&ctx)
-        || ctx.update.text().to_owned()
-                            .map_or(false,
-                                    |ss| Url::parse(ss).is_ok())
-        || is_image(&ctx)
+    is_forward(ctx)
+        || ctx.update.text()
+                     .is_some_and(|ss| url::Url::parse(ss).is_ok())
+        || !message_links(&ctx.update).is_empty()
+        || is_media(ctx)
 }
 
 async fn handle_command(ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
-                        db: Arc<Mutex<MyDB>>,
-                        top_db: Arc<Mutex<sled::Db>>) -> Result<bool, RequestError> {
-    let bot_name_str = BOT_NAME;
+                        detector: Arc<DuplicateDetector>) -> Result<bool, RequestError> {
+    let bot_name_str = bot_name();
     if let Some(text) = ctx.update.text() {
         if let Ok(command) = Command::parse(text, bot_name_str) {
-            // dbg!(&command);
-            if text.contains(bot_name_str) || reply_to_bot(&ctx){
-                action(&ctx, command, db, top_db).await?;
+            if text.contains(bot_name_str) || reply_to_bot(ctx){
+                action(ctx, command, detector).await?;
                 return Ok(true)
             } else {
                 return Ok(false)
             }
         }
     }
-    return Ok(false)
+    Ok(false)
 }
 
 async fn action(
     ctx: &UpdateWithCx<AutoSend<Bot>, Message>,
     command: Command,
-    db: Arc<Mutex<MyDB>>,
-    top_db: Arc<Mutex<sled::Db>>
+    detector: Arc<DuplicateDetector>,
 ) -> Result<(), RequestError> {
     match command {
         Command::Help => {
@@ -1135,55 +777,86 @@ async fn action(
         },
         Command::Delete => {
             info!("Handling delete request");
-            delete_replied_msg(&ctx).await?
+            delete_replied_msg(ctx).await?
         },
         Command::Top => {
             info!("Handling top board request");
-            let chat_id = get_chat_id(&ctx);
-            print_top_board(&ctx, &top_db, &chat_id).await;
+            let chat_id = get_chat_id(ctx);
+            print_top_board(ctx, &detector, &chat_id).await;
         },
         Command::Topics => {
             info!("Show topics");
-            let chat_id = get_chat_id(&ctx);
-            print_topics(&ctx, db, &chat_id).await;
+            let chat_id = get_chat_id(ctx);
+            print_topics(ctx, &detector, &chat_id).await;
         },
         Command::Me => {
             info!("Handling me request");
-            print_my_number(&ctx, &top_db).await;
+            print_my_number(ctx, &detector).await;
         },
         Command::ResetTop => {
             info!("Handling ResetTop request");
-            if is_admin(&ctx) {
+            if is_admin(ctx) {
                 info!("Resetting top board for current chat");
-                reset_top_board(&ctx, &top_db).await;
+                reset_top_board(ctx, &detector).await;
             }
+        },
+        Command::Config(args) => {
+            info!("Handling config request {:?}", &args);
+            if is_admin(ctx) {
+                configure(ctx, &detector, &args).await?;
+            } else {
+                ctx.reply_to("只有管理员才能查看或修改本群设置").send().await?;
+            }
+        },
+        Command::Ignore(args) | Command::Track(args) | Command::Normalize(args) | Command::Unignore(args)
+            if !is_admin(ctx) => {
+            info!("Non-admin trying to change rules with {:?}", &args);
+            ctx.reply_to("只有管理员才能修改规则").send().await?;
+        },
+        Command::Ignore(args) => {
+            info!("Handling ignore request {:?}", &args);
+            edit_rules(ctx, &detector, Some(RuleAction::Ignore), &args).await?;
+        },
+        Command::Track(args) => {
+            info!("Handling track request {:?}", &args);
+            edit_rules(ctx, &detector, Some(RuleAction::Track), &args).await?;
+        },
+        Command::Normalize(args) => {
+            info!("Handling normalize request {:?}", &args);
+            edit_rules(ctx, &detector, Some(RuleAction::Normalize), &args).await?;
+        },
+        Command::Unignore(args) => {
+            info!("Handling unignore request {:?}", &args);
+            edit_rules(ctx, &detector, None, &args).await?;
+        },
+        Command::Rules => {
+            info!("Handling rules request");
+            print_rules(ctx, &detector).await?;
         }
     };
 
     Ok(())
 }
 
-async fn run(db: Arc<Mutex<MyDB>>,
-             img_db: Arc<Mutex<sled::Db>>,
-             top_db: Arc<Mutex<sled::Db>>) {
+async fn run(bot: AutoSend<Bot>, detector: Arc<DuplicateDetector>) -> Result<()> {
     info!("Starting simple_commands_bot...");
 
-    let bot = Bot::from_env().auto_send();
-
-    // bot.set_my_commands(vec![teloxide::types::BotCommand::new("help", "delete")]).send().await.unwrap();
+    let me = bot.get_me().await.map_err(|e| anyhow!("getMe failed with error {:?}", e))?;
+    info!("Running as @{} ({})", me.user.username.as_deref().unwrap_or_default(), me.user.id);
+    // only set once, so will never fail
+    ME.set(me.user).unwrap();
 
-    let db = db.clone();
     teloxide::repl(bot, move |ctx| {
-        let db = db.clone();
-        let img_db = img_db.clone();
-        let top_db = top_db.clone();
+        let detector = detector.clone();
         async move {
-            match handle_command(&ctx, db.clone(), top_db.clone()).await {
+            match handle_command(&ctx, detector.clone()).await {
                 Ok(true) => {
                     info!("Command handled successfully");
                 },
                 Ok(false) | Err(_) => {
-                    if need_handle(&ctx) {
+                    if let (true, Some(media_group_id)) = (need_handle(&ctx), ctx.update.media_group_id()) {
+                        buffer_album(&ctx, detector, media_group_id);
+                    } else if need_handle(&ctx) {
                         // TODO: think of a better way to do it.
                         // Currently decided to suppress this error.
                         // teloxide seem to want a RequestError, while we would want a general Error
@@ -1206,12 +879,11 @@ async fn run(db: Arc<Mutex<MyDB>>,
                                                name = &group_title,
                                                by = &user,
                                                username = &username);
-                        parse_message(&ctx, db, img_db, top_db)
+                        if let Err(e) = parse_message(&ctx, detector)
                             .instrument(group_span)
-                            .await.err().map(
-                            |e|
+                            .await {
                             warn!("parse_message see error {:?}", e)
-                        );
+                        }
                     }
                 },
             }
@@ -1219,38 +891,342 @@ async fn run(db: Arc<Mutex<MyDB>>,
         }
     })
     .await;
+    Ok(())
 }
 
-fn get_env() {
-    let env_key = "NO_DUP_BOT_ADMIN";
-    let mut admin_db = HashSet::new();
-    let admin_str = match env::var_os(&env_key) {
-        Some(v) => v.into_string().unwrap(),
-        None => {
-            warn!("${} is not set", &env_key);
-            String::from("0")
+fn new_bot(config: &Config) -> AutoSend<Bot> {
+    match &config.token {
+        Some(token) => Bot::new(token).auto_send(),
+        None => Bot::from_env().auto_send(),
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "no_dup_bot", about = "Points out links, forwards and images already sent to a Telegram group")]
+struct Cli {
+    /// Config file, no_dup_bot.toml is read if it exists
+    #[structopt(short, long, env = "NO_DUP_BOT_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// Runs the bot, the default
+    Run,
+    /// Writes the records of one or every chat to stdout as JSON Lines
+    Export {
+        #[structopt(long, allow_hyphen_values = true)]
+        chat: Option<i64>,
+    },
+    /// Merges a file written by export into the storage
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Shows how many records are kept for each chat
+    Stats {
+        #[structopt(long, allow_hyphen_values = true)]
+        chat: Option<i64>,
+    },
+    /// Removes expired records once
+    Gc {
+        /// Only show what would be removed
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Shows the settings, rules and top board of a chat, or the record of a link in it
+    #[structopt(setting = structopt::clap::AppSettings::AllowNegativeNumbers)]
+    Inspect {
+        chat: i64,
+        url: Option<String>,
+    },
+    /// Copies databases of the old layout into the current one
+    Migrate,
+    /// Re-hashes the stored images with the configured algorithm
+    Rehash,
+}
+
+// Re-hashes the stored images with the configured algorithm, the bot should not be running
+async fn rehash(bot: AutoSend<Bot>, detector: &DuplicateDetector) {
+    let report = detector.rehash_images(|file_id| {
+        let bot = bot.clone();
+        async move {
+            match download_file(&bot, &file_id).await {
+                Ok(buf) => buf,
+                Err(e) => {
+                    warn!("Download of {:?} failed with error {:?}", &file_id, e);
+                    None
+                }
+            }
         }
-    };
-    for id in admin_str.split(":"){
-        let admin = match id.parse::<i64>() {
-            Ok(id) => id,
-            Err(_) => 0
-        };
-        admin_db.insert(admin);
+    }).await;
+    info!("Re-hashed {} images, skipped {} without a file id, {} failed",
+          report.rehashed, report.skipped, report.failed);
+}
+
+// Writes the records of `chat_id`, or of every chat, to stdout
+fn export(storage: &dyn Storage, chat_id: Option<i64>) -> Result<usize> {
+    let chat_id = chat_id.map(clean_chat_id);
+    let stdout = std::io::stdout();
+    dump::export(storage, chat_id.as_deref(), &mut std::io::BufWriter::new(stdout.lock()))
+}
+
+// Merges a dump written by `export` into the storage, the bot should not be running
+fn import(storage: &dyn Storage, path: &Path) -> Result<dump::ImportReport> {
+    let file = std::fs::File::open(path)?;
+    dump::import(storage, &mut std::io::BufReader::new(file))
+}
+
+// Prints the number of records of each chat
+fn stats(storage: &dyn Storage, chat_id: Option<i64>) -> Result<()> {
+    let chat_id = chat_id.map(clean_chat_id);
+    let chat_id = chat_id.as_deref();
+    // messages, images, files and users of each chat
+    let mut counts: BTreeMap<String, [usize; 4]> = BTreeMap::new();
+    for key in storage.messages(chat_id)?.into_iter().map(|(key, _)| key.chat_id) {
+        counts.entry(key).or_default()[0] += 1;
     }
-    // only set once, so will never fail
-    ADMIN.set(admin_db).unwrap();
+    for key in storage.images(chat_id)?.into_iter().map(|(key, _)| key.chat_id) {
+        counts.entry(key).or_default()[1] += 1;
+    }
+    for key in storage.files(chat_id)?.into_iter().map(|(key, _)| key.chat_id) {
+        counts.entry(key).or_default()[2] += 1;
+    }
+    for key in storage.users(chat_id)?.into_iter().map(|(key, _)| key.chat_id) {
+        counts.entry(key).or_default()[3] += 1;
+    }
+
+    println!("{:<16} {:>10} {:>10} {:>10} {:>10}", "chat", "messages", "images", "files", "users");
+    let mut total = [0; 4];
+    for (chat, count) in &counts {
+        println!("{:<16} {:>10} {:>10} {:>10} {:>10}", chat, count[0], count[1], count[2], count[3]);
+        for (total, count) in total.iter_mut().zip(count) {
+            *total += count;
+        }
+    }
+    if chat_id.is_none() {
+        println!("{:<16} {:>10} {:>10} {:>10} {:>10}", "total", total[0], total[1], total[2], total[3]);
+        println!("{} chats with their own settings, {} corrupt records in the quarantine",
+                 storage.all_settings()?.len(), storage.quarantined()?.len());
+    }
+    Ok(())
+}
+
+// Prints what is kept for a chat, or for a single link in it
+fn inspect(storage: &dyn Storage, chat_id: i64, url: Option<&str>) -> Result<()> {
+    let chat_id = clean_chat_id(chat_id);
+    if let Some(url) = url {
+        let key = MessageKey { chat_id, url: url::Url::parse(url)? };
+        match storage.find_message(&key)? {
+            Some(info) => println!("{}", serde_json::to_string_pretty(&info)?),
+            None => println!("No record of {} in {}", &key.url, &key.chat_id),
+        }
+        return Ok(());
+    }
+
+    match storage.find_settings(&chat_id)? {
+        Some(settings) => print!("Settings:\n{}", settings),
+        None => println!("Settings: the defaults"),
+    }
+    println!("Rules:");
+    for rule in storage.find_rules(&chat_id)?.unwrap_or_default() {
+        println!("{}", rule);
+    }
+    println!("Top board:");
+    let mut users = storage.users(Some(&chat_id))?;
+    users.sort_by_key(|(_, value)| std::cmp::Reverse(value.count));
+    for (key, value) in users {
+        println!("{:>6} {} ({})", value.count, value.username.as_deref().unwrap_or("?"), key.user_id);
+    }
+    println!("{} messages, {} images, {} files",
+             storage.messages(Some(&chat_id))?.len(), storage.images(Some(&chat_id))?.len(),
+             storage.files(Some(&chat_id))?.len());
+    Ok(())
 }
 
+// Every failure ends with an error status, so that scripts such as sync.sh
+// stop there
 #[tokio::main]
-async fn main() {
-    get_env();
-    tracing_subscriber::fmt::init();
-    // tracing_subscriber::fmt()
-    //     .pretty()
-    //     .init();
-    let db = Arc::new(Mutex::new(MyDB::init("bot_db")));
-    let img_db = Arc::new(Mutex::new(sled::open("img_db").unwrap()));
-    let top_db = Arc::new(Mutex::new(sled::open("top_db").unwrap()));
-    run(db.clone(), img_db.clone(), top_db.clone()).await;
+async fn main() -> Result<()> {
+    let cli = Cli::from_args();
+    let config = Config::load(cli.config.as_deref())?;
+    let level = config.log_level().unwrap_or_else(|e| {
+        eprintln!("{}, using info", e);
+        Level::INFO
+    });
+    let command = cli.command.unwrap_or(Subcommand::Run);
+    if let Subcommand::Export { .. } = command {
+        // stdout is for the dump
+        tracing_subscriber::fmt().with_max_level(level).with_writer(std::io::stderr).init();
+    } else {
+        tracing_subscriber::fmt().with_max_level(level).init();
+    }
+
+    if config.admins.is_empty() {
+        warn!("No admins are configured");
+    }
+    // only set once, so will never fail
+    ADMIN.set(config.admins.iter().copied().collect()).unwrap();
+
+    if let Subcommand::Migrate = command {
+        let report = layout::migrate(&config.data_dir).map_err(|e| anyhow!("Migration failed with error {:?}", e))?;
+        info!("Copied {} records from the old layout, migrated {} keys, {} were current already, \
+               {} unknown left as they are",
+              report.copied, report.migrated, report.current, report.failed);
+        return Ok(());
+    }
+    let storage = config.storage_config().map_err(|e| anyhow!("Storage: {}", e))?;
+    match &command {
+        Subcommand::Export { .. } | Subcommand::Import { .. } | Subcommand::Stats { .. }
+        | Subcommand::Inspect { .. } => {
+            let opened = storage.open().map_err(|e| anyhow!("Failed to open the {}: {}", &storage, e))?;
+            let result = match command {
+                Subcommand::Export { chat } => export(&*opened, chat)
+                    .map(|count| info!("Exported {} records", count)),
+                Subcommand::Import { file } => import(&*opened, &file)
+                    .map(|report| info!("Imported {} new records, merged {} with existing ones, {} failed",
+                                        report.added, report.merged, report.failed)),
+                Subcommand::Stats { chat } => stats(&*opened, chat),
+                Subcommand::Inspect { chat, url } => inspect(&*opened, chat, url.as_deref()),
+                _ => Ok(()),
+            };
+            return result.map_err(|e| anyhow!("Failed with error {:?}", e));
+        },
+        _ => (),
+    }
+
+    let detector = DuplicateDetector::open(&storage).map_err(|e| anyhow!("Failed to open the {}: {}", &storage, e))?;
+    let quarantined = detector.quarantined();
+    if !quarantined.is_empty() {
+        warn!("{} corrupt records were set aside in the quarantine", quarantined.len());
+    }
+    let detector = detector
+        .with_defaults(config.default_settings())
+        .with_hash_config(config.hash_config()?);
+    match command {
+        Subcommand::Rehash => rehash(new_bot(&config), &detector).await,
+        Subcommand::Gc { dry_run } => {
+            let mut gc_config = config.gc_config()?;
+            gc_config.dry_run |= dry_run;
+            let report = detector.collect_garbage(&gc_config).await;
+            let total = report.total();
+            info!("{} {} keys and {} bytes: {:?}",
+                  if gc_config.dry_run { "Dry run would reclaim" } else { "Reclaimed" },
+                  total.keys, total.bytes, &report);
+        },
+        _ => {
+            let detector = Arc::new(detector);
+            spawn_gc(detector.clone(), config.gc_config()?);
+            run(new_bot(&config), detector).await?;
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setting_values_keep_their_whitespace() {
+        let text = "/config set link_template 第一行\n  {url}   {count}";
+        assert_eq!(setting_value(text, "@bot"), "第一行\n  {url}   {count}");
+        let text = "/config@bot set\nlink_template\n\n{url} @bot";
+        assert_eq!(setting_value(text, "@bot"), "\n{url}");
+        assert_eq!(setting_value("/config @bot set window_hours 12", "@bot"), "12");
+        assert_eq!(setting_value("/config set window_hours", "@bot"), "");
+    }
+
+    // photos `indices` of an album, each seen before once
+    fn found(indices: &[usize]) -> Vec<(usize, Duplicate, Occurrence)> {
+        indices.iter().map(|i| {
+            let original = Occurrence { seen_at: chrono::Utc::now(), link: None, user_id: Some(1),
+                                        username: Some(String::from("someone")), message_id: Some(*i as i32) };
+            let url = url::Url::parse(&format!("https://img.telegram.com/{}", i)).unwrap();
+            let duplicate = Duplicate { url, kind: no_dup_core::DuplicateKind::Image, count: 2,
+                                        originals: vec![original.clone()] };
+            (*i, duplicate, original)
+        }).collect()
+    }
+
+    #[test]
+    fn album_replies_name_the_photos_seen_before() {
+        let settings = ChatSettings::default();
+        let msg = album_msg(&settings, 3, &found(&[1, 3]));
+        assert!(msg.starts_with("这个相册的第1、3个来过本群了："), "{}", msg);
+        assert!(msg.contains("\n\n第1个：") && msg.contains("\n\n第3个："), "{}", msg);
+        assert!(!msg.contains("第2个"), "{}", msg);
+        let msg = album_msg(&settings, 2, &found(&[1, 2]));
+        assert!(msg.starts_with("这个相册的2个全部来过本群了："), "{}", msg);
+    }
+
+    #[test]
+    fn albums_are_buffered_until_taken() {
+        let albums = Albums { albums: Default::default() };
+        assert!(albums.take("1").is_empty());
+        assert!(albums.push("1", 11));
+        assert!(albums.push("2", 21));
+        assert!(!albums.push("1", 12));
+        assert_eq!(albums.take("1"), vec![11, 12]);
+        // the next message of the album starts it over
+        assert!(albums.push("1", 13));
+        assert_eq!(albums.take("2"), vec![21]);
+    }
+
+    static LINK: &str = "https://example.com/some/long/path";
+
+    // A detector that saw `LINK` in messages 1 to `n` of a chat, and what the
+    // last one duplicates
+    async fn seen(n: i32) -> (DuplicateDetector, Duplicate) {
+        let detector = DuplicateDetector::new(Arc::new(no_dup_core::MemoryStorage::default()));
+        let mut last = None;
+        for message_id in 1..=n {
+            let msg = IncomingMessage { chat_id: 1, message_id, text: Some(String::from(LINK)), ..Default::default() };
+            last = Some(detector.check(&msg).await.unwrap());
+        }
+        match last {
+            Some(Verdict::Duplicate(mut duplicates)) => (detector, duplicates.remove(0)),
+            verdict => panic!("{:?} is not a duplicate", verdict),
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_originals_are_skipped_and_forgotten() {
+        let (detector, duplicate) = seen(4).await;
+        let probed = std::sync::Mutex::new(vec![]);
+        let probe = |id| {
+            probed.lock().unwrap().push(id);
+            std::future::ready(if id == 3 { Existence::Deleted } else { Existence::Exists })
+        };
+        let original = surviving_original(&detector, "1", &duplicate, probe).await;
+        assert_eq!(original.and_then(|o| o.message_id), Some(2));
+        assert_eq!(*probed.lock().unwrap(), vec![3, 2]);
+        // not offered as an original again
+        let msg = IncomingMessage { chat_id: 1, message_id: 5, text: Some(String::from(LINK)), ..Default::default() };
+        match detector.check(&msg).await.unwrap() {
+            Verdict::Duplicate(duplicates) => {
+                let originals: Vec<_> = duplicates[0].originals.iter().map(|o| o.message_id).collect();
+                assert_eq!(originals, vec![Some(1), Some(2), Some(4)]);
+            },
+            verdict => panic!("{:?} is not a duplicate", verdict),
+        }
+    }
+
+    #[tokio::test]
+    async fn originals_that_cannot_be_probed_count_as_existing() {
+        let (detector, mut duplicate) = seen(3).await;
+        let deleted = |_| std::future::ready(Existence::Deleted);
+        assert_eq!(surviving_original(&detector, "1", &duplicate, deleted).await, None);
+
+        let unknown = |_| std::future::ready(Existence::Unknown);
+        let original = surviving_original(&detector, "1", &duplicate, unknown).await;
+        assert_eq!(original.and_then(|o| o.message_id), Some(2));
+
+        // recorded before message ids were kept
+        duplicate.originals.iter_mut().for_each(|o| o.message_id = None);
+        let not_probed = |id| -> std::future::Ready<Existence> { panic!("probed {}", id) };
+        assert!(surviving_original(&detector, "1", &duplicate, not_probed).await.is_some());
+    }
 }
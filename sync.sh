@@ -1,20 +1,28 @@
 #!/usr/bin/env bash
+# Moves the state of the bot between here and the server as a dump, see
+# `no_dup_bot export` and `no_dup_bot import`. Records are merged on import, so
+# nothing on the receiving side is lost. Stop the bot on both sides first, sled
+# cannot be opened twice.
 
-rsync_synchronize='rsync -avzu --delete --info=progress2 -h'
+set -euo pipefail
 
-case $1 in
+server=linode
+server_dir=git/no_dup_bot
+release=target/x86_64-unknown-linux-musl/release/no_dup_bot
+dump=dump.jsonl
+
+case ${1:-} in
     "down")
         echo "syncing from server"
-        $rsync_synchronize linode:git/no_dup_bot/img_db ./
-        $rsync_synchronize linode:git/no_dup_bot/bot_db ./
-        $rsync_synchronize linode:git/no_dup_bot/top_db ./
+        ssh $server "cd $server_dir && ~/no_dup_bot export" > $dump
+        $release import $dump
         ;;
     "up")
         echo "syncing to server"
-        $rsync_synchronize ./img_db linode:git/no_dup_bot
-        $rsync_synchronize ./bot_db linode:git/no_dup_bot
-        $rsync_synchronize ./top_db linode:git/no_dup_bot
-        scp target/x86_64-unknown-linux-musl/release/no_dup_bot linode:
+        scp $release $server:
+        $release export > $dump
+        scp $dump $server:$server_dir/
+        ssh $server "cd $server_dir && ~/no_dup_bot import $dump"
         ;;
     *)
         echo "Only support ./sync.sh up|down"